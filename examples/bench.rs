@@ -0,0 +1,206 @@
+// Drives the tcpproxy binary against an in-process echo (or discard) backend
+// and reports throughput and the CPU time the proxy spent per connection.
+//
+//   cargo build --release && cargo run --release --example bench -- -n 100 -m 1M
+
+extern crate libc;
+
+use std::env;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{self, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Options {
+    conns: usize,
+    size: usize,
+    discard: bool,
+    proxy: PathBuf,
+    proxy_args: Vec<String>,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: bench [-n conns] [-m size] [-discard] [-proxy path] [-- proxy args...]");
+    process::exit(2);
+}
+
+fn parse_size(s: &str) -> Option<usize> {
+    let (num, shift) = match s.as_bytes().last() {
+        Some(b'K') | Some(b'k') => (&s[..s.len() - 1], 10),
+        Some(b'M') | Some(b'm') => (&s[..s.len() - 1], 20),
+        _ => (s, 0),
+    };
+    num.parse::<usize>().ok().map(|n| n << shift)
+}
+
+fn parse_args() -> Options {
+    // the proxy sits next to the examples directory of the same profile
+    let proxy = env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent()?.parent().map(|dir| dir.join("tcpproxy")))
+        .unwrap_or_else(|| PathBuf::from("tcpproxy"));
+    let mut opts = Options {
+        conns: 50,
+        size: 1 << 20,
+        discard: false,
+        proxy,
+        proxy_args: Vec::new(),
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-n" => {
+                opts.conns = args
+                    .next()
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "-m" => {
+                opts.size = args
+                    .next()
+                    .and_then(|s| parse_size(&s))
+                    .unwrap_or_else(|| usage())
+            }
+            "-discard" => opts.discard = true,
+            "-proxy" => opts.proxy = args.next().map(PathBuf::from).unwrap_or_else(|| usage()),
+            "--" => opts.proxy_args.extend(args.by_ref()),
+            _ => usage(),
+        }
+    }
+    opts
+}
+
+fn backend(discard: bool) -> io::Result<u16> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let port = listener.local_addr()?.port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            thread::spawn(move || {
+                if discard {
+                    let _ = io::copy(&mut stream, &mut io::sink());
+                } else if let Ok(mut reader) = stream.try_clone() {
+                    let _ = io::copy(&mut reader, &mut stream);
+                    let _ = stream.shutdown(Shutdown::Write);
+                }
+            });
+        }
+    });
+    Ok(port)
+}
+
+fn free_port() -> io::Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+// pushes size bytes and, unless discarding, reads them back; true when
+// everything made it through
+fn one(port: u16, size: usize, discard: bool) -> io::Result<bool> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port))?;
+    let mut writer = stream.try_clone()?;
+    let sender = thread::spawn(move || -> io::Result<()> {
+        let chunk = [0x5au8; 65536];
+        let mut left = size;
+        while left > 0 {
+            let n = left.min(chunk.len());
+            writer.write_all(&chunk[..n])?;
+            left -= n;
+        }
+        writer.shutdown(Shutdown::Write)
+    });
+    let mut buf = [0u8; 65536];
+    let mut received = 0;
+    loop {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        received += n;
+    }
+    let sent = sender
+        .join()
+        .unwrap_or_else(|_| Err(io::ErrorKind::Other.into()));
+    Ok(sent.is_ok() && (discard || received == size))
+}
+
+fn main() {
+    let opts = parse_args();
+    let backend_port = backend(opts.discard).unwrap_or_else(|e| {
+        eprintln!("backend: {}", e);
+        process::exit(1);
+    });
+    let port = free_port().unwrap_or_else(|e| {
+        eprintln!("port: {}", e);
+        process::exit(1);
+    });
+    let mut child = Command::new(&opts.proxy)
+        .arg("-l")
+        .arg(format!("127.0.0.1:{}", port))
+        .arg("-d")
+        .arg(format!("127.0.0.1:{}", backend_port))
+        .args(&opts.proxy_args)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap_or_else(|e| {
+            eprintln!("{}: {}", opts.proxy.display(), e);
+            process::exit(1);
+        });
+
+    // the proxy is up once its listener accepts
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        if Instant::now() > deadline {
+            eprintln!("proxy did not come up");
+            let _ = child.kill();
+            process::exit(1);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let start = Instant::now();
+    let workers: Vec<_> = (0..opts.conns)
+        .map(|_| {
+            let (size, discard) = (opts.size, opts.discard);
+            thread::spawn(move || one(port, size, discard).unwrap_or(false))
+        })
+        .collect();
+    let ok = workers
+        .into_iter()
+        .map(|worker| worker.join().unwrap_or(false))
+        .filter(|&ok| ok)
+        .count();
+    let elapsed = start.elapsed();
+
+    let _ = child.kill();
+    let _ = child.wait();
+    let mut usage: libc::rusage = unsafe { mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage) };
+
+    let secs = elapsed.as_secs_f64();
+    let moved = if opts.discard { 1 } else { 2 } * opts.size * ok;
+    let per_conn = |us: f64| us / opts.conns as f64;
+    let user = usage.ru_utime.tv_sec as f64 * 1e6 + usage.ru_utime.tv_usec as f64;
+    let sys = usage.ru_stime.tv_sec as f64 * 1e6 + usage.ru_stime.tv_usec as f64;
+    println!(
+        "{} of {} connections x {} bytes in {:.3}s",
+        ok, opts.conns, opts.size, secs
+    );
+    println!(
+        "{:.1} conn/s, {:.1} MB/s through the proxy",
+        opts.conns as f64 / secs,
+        moved as f64 / secs / (1 << 20) as f64
+    );
+    println!(
+        "proxy cpu per connection {:.0}us (user {:.0}us, sys {:.0}us), {:.1} context switches",
+        per_conn(user + sys),
+        per_conn(user),
+        per_conn(sys),
+        per_conn((usage.ru_nvcsw + usage.ru_nivcsw) as f64)
+    );
+}
@@ -0,0 +1,144 @@
+use libc;
+use std::ffi::CString;
+use std::fs;
+use std::mem;
+use std::os::unix::fs::FileTypeExt;
+use std::ptr;
+use std::time::{Duration, Instant};
+use sys::SysResult;
+
+// a control client gets this long for all of its command line
+pub const LINE_TIMEOUT: Duration = Duration::from_millis(100);
+// and any client this long to take all of the reply
+const REPLY_TIMEOUT: Duration = Duration::from_millis(100);
+pub const MAX_LINE: usize = 512;
+
+// unix stream listener for the control commands, a stale socket left behind
+// at path is replaced
+pub fn listen(path: &str) -> SysResult<i32> {
+    if let Ok(meta) = fs::metadata(path) {
+        if meta.file_type().is_socket() {
+            let _ = fs::remove_file(path);
+        }
+    }
+    let mut sun: libc::sockaddr_un = unsafe { mem::zeroed() };
+    if path.len() >= sun.sun_path.len() {
+        return Err(libc::ENAMETOOLONG);
+    }
+    let cpath = CString::new(path).map_err(|_| libc::EINVAL)?;
+    sun.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dst, &src) in sun.sun_path.iter_mut().zip(cpath.as_bytes()) {
+        *dst = src as libc::c_char;
+    }
+    let fd = syscall!(libc::socket(
+        libc::AF_UNIX,
+        libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+        0
+    ))?;
+    let r = syscall!(libc::bind(
+        fd,
+        &sun as *const _ as *const libc::sockaddr,
+        mem::size_of_val(&sun) as libc::socklen_t
+    ))
+    .and_then(|_| syscall!(libc::listen(fd, 16)));
+    if let Err(e) = r {
+        unsafe { libc::close(fd) };
+        return Err(e);
+    }
+    Ok(fd)
+}
+
+// a nonblocking client fd, it is watched in the epoll set like the
+// connections
+pub fn accept(listen_fd: i32) -> SysResult<i32> {
+    syscall!(libc::accept4(
+        listen_fd,
+        ptr::null_mut(),
+        ptr::null_mut(),
+        libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC
+    ))
+}
+
+// a client of the control socket or the -health listener. What it sends is
+// gathered until its request is complete, the reply goes out as the socket
+// takes it. Each of the two has until deadline
+pub struct Client {
+    input: Vec<u8>,
+    eof: bool,
+    output: Vec<u8>,
+    sent: usize,
+    pub deadline: Instant,
+}
+
+impl Client {
+    pub fn new(deadline: Instant) -> Client {
+        Client {
+            input: Vec::new(),
+            eof: false,
+            output: Vec::new(),
+            sent: 0,
+            deadline,
+        }
+    }
+
+    // reads what came in, up to max bytes of it in all
+    pub fn read(&mut self, fd: i32, max: usize) -> SysResult<()> {
+        let mut buf = [0u8; 1024];
+        while !self.eof && self.input.len() < max {
+            let len = buf.len().min(max - self.input.len());
+            match syscall!(libc::read(fd, buf.as_mut_ptr() as *mut _, len)) {
+                Ok(0) => self.eof = true,
+                Ok(n) => self.input.extend_from_slice(&buf[..n as usize]),
+                Err(e) if e == libc::EAGAIN => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn input(&self) -> &[u8] {
+        &self.input
+    }
+
+    pub fn eof(&self) -> bool {
+        self.eof
+    }
+
+    pub fn replying(&self) -> bool {
+        !self.output.is_empty()
+    }
+
+    pub fn reply(&mut self, output: Vec<u8>) {
+        self.output = output;
+        self.deadline = Instant::now() + REPLY_TIMEOUT;
+    }
+
+    // sends what the socket takes of the reply, true once all of it is out
+    pub fn flush(&mut self, fd: i32) -> SysResult<bool> {
+        while self.sent < self.output.len() {
+            let rest = &self.output[self.sent..];
+            match syscall!(libc::send(
+                fd,
+                rest.as_ptr() as *const _,
+                rest.len(),
+                libc::MSG_NOSIGNAL
+            )) {
+                Ok(n) => self.sent += n as usize,
+                Err(e) if e == libc::EAGAIN => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+}
+
+// a command line is complete at its line end, or when it cannot grow more
+pub fn line_complete(data: &[u8]) -> bool {
+    data.contains(&b'\n') || data.len() >= MAX_LINE
+}
+
+// the first line the client sent, without the line end
+pub fn first_line(data: &[u8]) -> String {
+    let end = data.iter().position(|&b| b == b'\n').unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).trim().to_string()
+}
@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+// largest request head looked at for the Host header, unless -header-max
+pub const MAX_HEAD: usize = 8192;
+
+// how long a -health client gets to send its request head, all of it
+pub const HEAD_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub enum Head {
+    // no end of the head yet, more has to come in
+    Incomplete,
+    // not an HTTP/1.x request
+    NotHttp,
+    // a request head without its end within max bytes
+    TooLarge,
+    // the Host header, lowercased and without port, if the request had one
+    Host(Option<String>),
+}
+
+// looks at the first request of a client as far as it came in, a head
+// may take max bytes
+pub fn request_host(data: &[u8], max: usize) -> Head {
+    let end = match data.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => end,
+        None => {
+            // an early look at the request line spares waiting for the rest
+            // of something that is not HTTP at all
+            return match data.iter().position(|&b| b == b'\n') {
+                Some(i) if !is_request_line(&data[..i]) => Head::NotHttp,
+                None if !data
+                    .iter()
+                    .all(|&b| b.is_ascii_graphic() || b == b' ' || b == b'\r') =>
+                {
+                    Head::NotHttp
+                }
+                _ if data.len() >= max => Head::TooLarge,
+                _ => Head::Incomplete,
+            };
+        }
+    };
+    let head = String::from_utf8_lossy(&data[..end]);
+    let mut lines = head.split("\r\n");
+    if !lines
+        .next()
+        .is_some_and(|line| is_request_line(line.as_bytes()))
+    {
+        return Head::NotHttp;
+    }
+    let host = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| strip_port(value.trim()).to_ascii_lowercase());
+    Head::Host(host)
+}
+
+// METHOD target HTTP/1.x
+fn is_request_line(line: &[u8]) -> bool {
+    let line = String::from_utf8_lossy(line);
+    let mut parts = line.trim_end_matches('\r').split(' ');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => {
+            !method.is_empty()
+                && method.bytes().all(|b| b.is_ascii_uppercase())
+                && !target.is_empty()
+                && (version == "HTTP/1.0" || version == "HTTP/1.1")
+        }
+        _ => false,
+    }
+}
+
+fn strip_port(host: &str) -> &str {
+    // [v6]:port keeps the brackets off as well
+    if let Some(rest) = host.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match host.rfind(':') {
+        Some(i) if host[i + 1..].bytes().all(|b| b.is_ascii_digit()) => &host[..i],
+        _ => host,
+    }
+}
+
+// the route of a host: exact names first, then *.suffix patterns in order
+pub fn match_host<'a, T>(routes: &'a [(String, T)], host: &str) -> Option<&'a T> {
+    routes
+        .iter()
+        .find(|(name, _)| name == host)
+        .or_else(|| {
+            routes.iter().find(|(name, _)| {
+                name.strip_prefix('*')
+                    .is_some_and(|suffix| suffix.starts_with('.') && host.ends_with(suffix))
+            })
+        })
+        .map(|(_, route)| route)
+}
+
+// a -health client is answered once its request head is in, read in full
+// so closing after the response does not reset it, or when the head
+// outgrows MAX_HEAD
+pub fn head_complete(data: &[u8]) -> bool {
+    data.windows(4).any(|w| w == b"\r\n\r\n") || data.len() >= MAX_HEAD
+}
+
+// 200 or 503 for a -health client, the connection closes after it
+pub fn health_response(healthy: bool) -> Vec<u8> {
+    let (status, body) = if healthy {
+        ("200 OK", "OK\n")
+    } else {
+        ("503 Service Unavailable", "unavailable\n")
+    };
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
@@ -1,463 +1,4910 @@
 extern crate libc;
+extern crate rustls;
 
+#[macro_use]
+mod sys;
+mod control;
+mod http;
+mod net;
+mod stats;
+mod timer;
+mod tls;
+mod upstream;
+
+use rustls::pki_types::ServerName;
 use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::env;
+use std::fmt;
+use std::fs;
 use std::mem;
-use std::net;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::process;
 use std::ptr;
-use std::rc::Rc;
-
-type SysResult<T> = Result<T, i32>;
+use std::rc::{Rc, Weak};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use sys::{epoll_add, epoll_del, epoll_fd, epoll_mod, pipe_size, SysResult};
 
-macro_rules! syscall {
-    ($e: expr) => {{
-        let r = unsafe { $e };
-        if r < 0 {
-            Err(unsafe { *libc::__errno_location() })
-        } else {
-            Ok(r)
-        }
-    }};
+// client address families a listener accepts, IPv4-mapped IPv6 counts as v4
+#[derive(Clone, Copy, PartialEq)]
+enum Family {
+    Both,
+    V4,
+    V6,
 }
 
-fn sa_to_raw(sa: &net::SocketAddrV4) -> libc::sockaddr_in {
-    let ip = sa.ip().octets();
-    libc::sockaddr_in {
-        sin_family: libc::AF_INET as libc::sa_family_t,
-        sin_port: sa.port().to_be(),
-        sin_addr: libc::in_addr {
-            s_addr: (ip[3] as u32) << 24
-                | (ip[2] as u32) << 16
-                | (ip[1] as u32) << 8
-                | (ip[0] as u32),
-        },
-        ..unsafe { mem::zeroed() }
+impl Family {
+    fn allows(self, addr: &SocketAddr) -> bool {
+        // the IPv4 clients of a dual stack listener come out of accept in
+        // their IPv4 form
+        match self {
+            Family::Both => true,
+            Family::V4 => addr.is_ipv4(),
+            Family::V6 => addr.is_ipv6(),
+        }
     }
-}
 
-fn sa6_to_raw(sa: &net::SocketAddrV6) -> libc::sockaddr_in6 {
-    let mut inaddr: libc::in6_addr = unsafe { mem::zeroed() };
-    inaddr.s6_addr = sa.ip().octets();
-    libc::sockaddr_in6 {
-        sin6_family: libc::AF_INET6 as libc::sa_family_t,
-        sin6_port: sa.port().to_be(),
-        sin6_flowinfo: sa.flowinfo(),
-        sin6_addr: inaddr,
-        sin6_scope_id: sa.scope_id(),
+    fn name(self) -> &'static str {
+        match self {
+            Family::Both => "both",
+            Family::V4 => "v4",
+            Family::V6 => "v6",
+        }
     }
 }
 
-fn connect_tcp(addr: &net::SocketAddr) -> SysResult<i32> {
-    let fd = syscall!(libc::socket(
-        match *addr {
-            net::SocketAddr::V4(_) => libc::AF_INET,
-            net::SocketAddr::V6(_) => libc::AF_INET6,
-        },
-        libc::SOCK_STREAM | libc::SOCK_NONBLOCK,
-        0,
-    ))?;
-    let r = match addr {
-        &net::SocketAddr::V4(sa) => {
-            let sin = sa_to_raw(&sa);
-            syscall!(libc::connect(
-                fd,
-                &sin as *const _ as *const _,
-                mem::size_of_val(&sin) as libc::socklen_t
-            ))
-        }
-        &net::SocketAddr::V6(sa) => {
-            let sin = sa6_to_raw(&sa);
-            syscall!(libc::connect(
-                fd,
-                &sin as *const _ as *const _,
-                mem::size_of_val(&sin) as libc::socklen_t
-            ))
-        }
-    };
-    if let Err(e) = r {
-        if e != libc::EINPROGRESS {
-            unsafe { libc::close(fd) };
-            return Err(e);
+impl FromStr for Family {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Family, ()> {
+        match s {
+            "both" => Ok(Family::Both),
+            "v4" => Ok(Family::V4),
+            "v6" => Ok(Family::V6),
+            _ => Err(()),
         }
     }
-    Ok(fd)
 }
 
-fn listen_tcp(addr: &net::SocketAddr) -> SysResult<i32> {
-    let fd = syscall!(libc::socket(
-        match *addr {
-            net::SocketAddr::V4(_) => libc::AF_INET,
-            net::SocketAddr::V6(_) => libc::AF_INET6,
-        },
-        libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
-        0,
-    ))?;
-    let r = match addr {
-        &net::SocketAddr::V4(sa) => {
-            let sin = sa_to_raw(&sa);
-            syscall!(libc::bind(
-                fd,
-                &sin as *const _ as *const _,
-                mem::size_of_val(&sin) as libc::socklen_t
-            ))
-        }
-        &net::SocketAddr::V6(sa) => {
-            let sin = sa6_to_raw(&sa);
-            syscall!(libc::bind(
-                fd,
-                &sin as *const _ as *const _,
-                mem::size_of_val(&sin) as libc::socklen_t
-            ))
+// the kinds of turned away clients -reject-response answers
+#[derive(Clone, Copy, PartialEq)]
+enum Reject {
+    // not of the -family of its listener
+    Family,
+    // dropped by -accept-age
+    AcceptStale,
+    // a backend connection that came around to the proxy
+    BackendLoop,
+    // no backend could be connected
+    ConnectFailed,
+    // no route by -http-route or -route-prefix within the header limits
+    Route,
+    // dropped by -shed or -shed-rate
+    Shed,
+}
+
+impl Reject {
+    fn name(self) -> &'static str {
+        match self {
+            Reject::Family => "family",
+            Reject::AcceptStale => "accept_stale",
+            Reject::BackendLoop => "backend_loop",
+            Reject::ConnectFailed => "connect_failed",
+            Reject::Route => "route",
+            Reject::Shed => "shed",
         }
-    };
-    if let Err(e) = r {
-        unsafe { libc::close(fd) };
-        return Err(e);
-    }
-    let r = syscall!(libc::listen(fd, libc::SOMAXCONN));
-    if let Err(e) = r {
-        unsafe { libc::close(fd) };
-        Err(e)
-    } else {
-        Ok(fd)
     }
 }
 
-static mut EPOLL_FD_: i32 = 0;
-static EPOLL_FD: &i32 = unsafe { &EPOLL_FD_ };
+impl FromStr for Reject {
+    type Err = ();
 
-fn epoll_add(fd: i32, rw: i32, data: u64) -> SysResult<i32> {
-    let mut events = libc::EPOLLET;
-    if rw & 1 != 0 {
-        events |= libc::EPOLLIN;
-    }
-    if rw & 2 != 0 {
-        events |= libc::EPOLLOUT;
+    fn from_str(s: &str) -> Result<Reject, ()> {
+        match s {
+            "family" => Ok(Reject::Family),
+            "accept_stale" => Ok(Reject::AcceptStale),
+            "backend_loop" => Ok(Reject::BackendLoop),
+            "connect_failed" => Ok(Reject::ConnectFailed),
+            "route" => Ok(Reject::Route),
+            "shed" => Ok(Reject::Shed),
+            _ => Err(()),
+        }
     }
-    syscall!(libc::epoll_ctl(
-        *EPOLL_FD,
-        libc::EPOLL_CTL_ADD,
-        fd,
-        &libc::epoll_event {
-            events: events as u32,
-            u64: data
-        } as *const _ as *mut _,
-    ))
 }
 
-fn epoll_del(fd: i32) -> SysResult<i32> {
-    syscall!(libc::epoll_ctl(
-        *EPOLL_FD,
-        libc::EPOLL_CTL_DEL,
-        fd,
-        ptr::null_mut(),
-    ))
-}
-
-static mut PIPE_SIZE_: isize = 0;
-static PIPE_SIZE: &isize = unsafe { &PIPE_SIZE_ };
-
-struct IoBuf {
-    pfd: [i32; 2],
-    buffered: isize,
+// one backend entry, those of a backend file remember their line for errors
+struct BackendSpec {
+    addr: String,
+    weight: u32,
+    // overrides -ct for this backend
+    connect_timeout: Option<Duration>,
+    origin: Option<String>,
 }
 
-impl IoBuf {
-    fn new() -> IoBuf {
-        let mut pfd = [0; 2];
-        syscall!(libc::pipe(pfd.as_mut_ptr())).unwrap();
-        IoBuf {
-            pfd,
-            buffered: 0,
+impl BackendSpec {
+    fn new(addr: &str, weight: u32, origin: Option<String>) -> BackendSpec {
+        BackendSpec {
+            addr: addr.to_string(),
+            weight,
+            connect_timeout: None,
+            origin,
         }
     }
 
-    fn is_empty(&self) -> bool {
-        self.buffered == 0
-    }
-
-    fn splice_in(&mut self, fd: i32) -> SysResult<bool> {
-        let max_size = *PIPE_SIZE;
-        while self.buffered < max_size {
-            let r = syscall!(libc::splice(
-                fd,
-                ptr::null_mut(),
-                self.pfd[1],
-                ptr::null_mut(),
-                (max_size - self.buffered) as usize,
-                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK
-            ));
-            let n = match r {
-                Ok(n) => n,
-                Err(e) => {
-                    if e == libc::EAGAIN {
-                        break;
-                    }
-                    return Err(e);
-                }
-            };
-            if n == 0 {
-                return Ok(true);
+    // host:port[*weight][;ct=d]
+    fn parse(entry: &str, origin: Option<String>) -> Result<BackendSpec, String> {
+        let mut opts = entry.split(';');
+        let entry = opts.next().unwrap_or_default().trim();
+        let mut connect_timeout = None;
+        for opt in opts {
+            match opt.trim().split_once('=') {
+                Some(("ct", value)) => match duration(value) {
+                    Some(timeout) => connect_timeout = Some(timeout),
+                    None => return Err(format!("invalid connect timeout in {}", entry)),
+                },
+                _ => return Err(format!("unknown option {} in {}", opt.trim(), entry)),
             }
-            self.buffered += n;
         }
-        Ok(false)
+        let (addr, weight) = match entry.rfind('*') {
+            Some(i) => match entry[i + 1..].parse::<u32>() {
+                Ok(weight) if weight > 0 => (&entry[..i], weight),
+                _ => return Err(format!("invalid weight in {}", entry)),
+            },
+            None => (entry, 1),
+        };
+        net::split_host_port(addr)?;
+        let mut spec = BackendSpec::new(addr, weight, origin);
+        spec.connect_timeout = connect_timeout;
+        Ok(spec)
     }
 
-    fn splice_out(&mut self, fd: i32) -> SysResult<()> {
-        while self.buffered > 0 {
-            let r = syscall!(libc::splice(
-                self.pfd[0],
-                ptr::null_mut(),
-                fd,
-                ptr::null_mut(),
-                self.buffered as usize,
-                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK
-            ));
-            let n = match r {
-                Ok(n) => n,
-                Err(e) => {
-                    if e == libc::EAGAIN {
-                        break;
-                    }
-                    return Err(e);
-                }
-            };
-            self.buffered -= n;
+    fn error(&self, e: String) -> String {
+        match self.origin {
+            Some(ref origin) => format!("{}: {}", origin, e),
+            None => e,
         }
-        Ok(())
     }
 }
 
-impl Drop for IoBuf {
-    fn drop(&mut self) {
-        unsafe {
-            libc::close(self.pfd[0]);
-            libc::close(self.pfd[1]);
-        }
-    }
+struct Config {
+    listen_addrs: Vec<String>,
+    listen_family: Family,
+    tls_listen_addrs: Vec<String>,
+    tls_listen_family: Family,
+    tls_cert: String,
+    tls_key: String,
+    listen_device: String,
+    // -inherit-fds, listening sockets bound by a supervisor
+    inherit_fds: Vec<i32>,
+    backends: Vec<BackendSpec>,
+    // -http-route picks the backend by the Host header of the first request
+    http_route: bool,
+    // -route-prefix picks it by a ROUTE: line the client sends first
+    route_prefix: bool,
+    // what -http-route and -route-prefix wait for at most before forwarding
+    header_max: Option<usize>,
+    header_timeout: Option<Duration>,
+    // -host name=host:port, in the order given
+    routes: Vec<(String, BackendSpec)>,
+    backend_tls: bool,
+    backend_sni: String,
+    backend_ca: String,
+    backend_insecure: bool,
+    // -srcaddr, a port other than 0 is kept in src_ports
+    src_addr: Option<SocketAddr>,
+    src_ports: Option<(u16, u16)>,
+    mptcp: bool,
+    // -sockopt, set on every client and backend socket of their side
+    client_sock_opts: Vec<net::SockOpt>,
+    backend_sock_opts: Vec<net::SockOpt>,
+    // -mark and -lmark, SO_MARK of the backend and the listen sockets
+    mark: Option<u32>,
+    listen_mark: Option<u32>,
+    // -md5, the TCP MD5 signature key of backend connections
+    md5_key: Vec<u8>,
+    socks5: String,
+    socks5_auth: String,
+    http_connect: String,
+    http_connect_auth: String,
+    connlimit: usize,
+    // -shed, the live connections and -shed-rate, the accepts per second
+    // above which new clients are dropped, 0 for no limit
+    shed_conns: usize,
+    shed_rate: u64,
+    // sent to a plaintext client before it is turned away, for the kinds
+    // of reject_on or any kind if that is empty
+    reject_response: Vec<u8>,
+    reject_on: Vec<Reject>,
+    max_events: usize,
+    // soft limit of open fds to raise to, 0 keeps it
+    nofile: u64,
+    connect_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    // data waiting in a pipe that its destination takes none of
+    stall_timeout: Option<Duration>,
+    // clients that waited longer in the accept queue are dropped
+    accept_age: Option<Duration>,
+    accept_delay: Option<Duration>,
+    // the listeners rest this long when accept runs out of fds
+    accept_cooldown: Duration,
+    // how long the proxy runs before it drains and exits
+    runtime: Option<Duration>,
+    // how long the drain after -runtime waits for the connections, the
+    // default when None
+    drain_timeout: Option<Duration>,
+    // how long a client IP sticks to the backend it was last sent to
+    sticky: Option<Duration>,
+    in_buf_size: isize,
+    out_buf_size: isize,
+    chunk_size: isize,
+    fair_bytes: isize,
+    lazy: bool,
+    retry_reset: bool,
+    transparent: bool,
+    redirect: bool,
+    sink: bool,
+    log_json: bool,
+    tcp_info: bool,
+    mirror: String,
+    control: String,
+    health: String,
+    syslog: String,
+    verbose: bool,
+    check: bool,
+    print_config: bool,
 }
 
-struct Context {
-    bad: bool,
-    client_fd: i32,
-    backend_fd: i32,
-    in_buf: IoBuf,
-    out_buf: IoBuf,
-    in_pd: u64,
-    out_pd: u64,
-}
+impl Config {
+    // (address, tls, family) of every listener, plaintext ones first
+    fn listeners(&self) -> Vec<(&str, bool, Family)> {
+        let plain = self
+            .listen_addrs
+            .iter()
+            .map(|addr| (addr.as_str(), false, self.listen_family));
+        let tls = self
+            .tls_listen_addrs
+            .iter()
+            .map(|addr| (addr.as_str(), true, self.tls_listen_family));
+        plain.chain(tls).collect()
+    }
 
-impl Context {
-    fn new(client_fd: i32, backend_fd: i32) -> Context {
-        Context {
-            bad: false,
-            client_fd,
-            backend_fd,
-            in_buf: IoBuf::new(),
-            out_buf: IoBuf::new(),
-            in_pd: 0,
-            out_pd: 0,
+    // the bytes a routed client may send before its header is complete
+    fn header_max(&self) -> usize {
+        match self.header_max {
+            Some(max) => max,
+            None if self.route_prefix => MAX_PREFIX,
+            None => http::MAX_HEAD,
         }
     }
 
-    fn copy(buf: &mut IoBuf, from_fd: i32, to_fd: i32) -> SysResult<()> {
-        let eof = buf.splice_in(from_fd)?;
-        if !buf.is_empty() {
-            buf.splice_out(to_fd)?;
-        }
-        if eof && buf.is_empty() {
-            Err(0)
-        } else {
-            Ok(())
-        }
+    fn drain_timeout(&self) -> Duration {
+        self.drain_timeout.unwrap_or(DRAIN_TIMEOUT)
     }
 
-    fn copy_from(&mut self) -> SysResult<()> {
-        if self.bad {
-            Err(0)
+    fn listen_device(&self) -> Option<&str> {
+        if self.listen_device.is_empty() {
+            None
         } else {
-            Context::copy(&mut self.in_buf, self.client_fd, self.backend_fd)
+            Some(&self.listen_device)
         }
     }
 
-    fn copy_to(&mut self) -> SysResult<()> {
-        if self.bad {
-            Err(0)
+    // address of the upstream proxy the backends are reached through
+    fn upstream(&self) -> Option<&str> {
+        if !self.socks5.is_empty() {
+            Some(&self.socks5)
+        } else if !self.http_connect.is_empty() {
+            Some(&self.http_connect)
         } else {
-            Context::copy(&mut self.out_buf, self.backend_fd, self.client_fd)
+            None
         }
     }
 
-    fn shutdown(&mut self) {
-        if !self.bad {
-            epoll_del(self.client_fd).unwrap();
-            epoll_del(self.backend_fd).unwrap();
-            mem::drop(unsafe { Box::from_raw(self.in_pd as *mut PollDesp) });
-            mem::drop(unsafe { Box::from_raw(self.out_pd as *mut PollDesp) });
-            self.bad = true
-        }
+    fn timeouts(&self) -> impl Iterator<Item = Duration> {
+        let backends = self
+            .backends
+            .iter()
+            .chain(self.routes.iter().map(|(_, spec)| spec))
+            .map(|spec| spec.connect_timeout);
+        vec![
+            self.connect_timeout,
+            self.idle_timeout,
+            self.read_timeout,
+            self.write_timeout,
+            self.stall_timeout,
+            self.header_timeout,
+            // expired entries are swept on the same tick
+            self.sticky,
+        ]
+        .into_iter()
+        .chain(backends.collect::<Vec<_>>())
+        .flatten()
     }
 }
 
-impl Drop for Context {
-    fn drop(&mut self) {
-        println!("Context drop: {}+{}", self.client_fd, self.backend_fd);
-        unsafe {
-            libc::close(self.client_fd);
-            libc::close(self.backend_fd);
-        }
-    }
+// bound of -header-max, what is looked at has to fit the socket buffer
+const MAX_HEADER: usize = 65536;
+
+// bounds of -maxevents, the events epoll_wait hands back at once
+const MIN_EVENTS: usize = 16;
+const MAX_EVENTS: usize = 65536;
+
+// default of -drain-timeout
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// default of -accept-cooldown
+const ACCEPT_COOLDOWN: Duration = Duration::from_millis(100);
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: tcpproxy [-l host:port[,port|-port...] [-family v4|v6|both]] [-tls-l host:port[,port|-port...] [-family v4|v6|both] -tls-cert file -tls-key file] [-ldev iface] [-inherit-fds fd[,fd...]] [-transparent | -redirect] [-d host:port[*weight][;ct=d][,...] | -d @file] [-http-route | -route-prefix [-host name=host:port...] [-header-max size] [-header-timeout d]] [-backend-tls [-backend-sni name] [-backend-ca file | -backend-insecure]] [-socks5 host:port [-socks5-auth user:pass] | -http-connect host:port [-http-connect-auth user:pass]] [-srcaddr ip:port] [-srcport port | -srcport-range lo-hi] [-mptcp] [-mark n] [-lmark n] [-md5 key|@file] [-sockopt [client:|backend:]level:name=value...] [-sticky ttl] [-connlimit n] [-shed n] [-shed-rate n] [-reject-response string|@file [-reject-on kind[,kind...]]] [-maxevents n] [-nofile n] [-ct d] [-timeout d] [-rtimeout d] [-wtimeout d] [-stall d] [-accept-age d] [-accept-delay d] [-accept-cooldown d] [-runtime d [-drain-timeout d]] [-b size[,size]] [-chunk size] [-fairbytes size] [-lazy] [-retry-reset] [-sink] [-log-json] [-tcpinfo] [-mirror host:port] [-control path] [-health host:port] [-syslog facility] [-v] [--check | --print-config]"
+    );
+    process::exit(2);
 }
 
-struct PollDesp {
-    who: i32,
-    ctx: Rc<RefCell<Context>>,
+fn invalid_value(name: &str) -> ! {
+    eprintln!("invalid value for {}", name);
+    usage();
 }
 
-impl Drop for PollDesp {
-    fn drop(&mut self) {
-        println!("PollDesp drop: {}", self.who);
+fn parse_value<T: FromStr>(name: &str, value: Option<String>) -> T {
+    match value.and_then(|s| s.parse().ok()) {
+        Some(v) => v,
+        None => invalid_value(name),
     }
 }
 
-fn handle_client(client_fd: i32) {
-    let res = connect_tcp(&"127.0.0.1:9527".parse().unwrap());
-    let backend_fd = match res {
-        Ok(fd) => fd,
-        Err(e) => {
-            println!("connect backend failed: {}", e);
-            unsafe { libc::close(client_fd) };
-            return;
-        }
+// -maxevents, a number within MIN_EVENTS and MAX_EVENTS
+fn parse_max_events(value: Option<String>) -> Option<usize> {
+    value
+        .and_then(|s| s.parse().ok())
+        .filter(|n| (MIN_EVENTS..=MAX_EVENTS).contains(n))
+}
+
+fn parse_size(s: &str) -> Option<isize> {
+    let (num, shift) = match s.as_bytes().last() {
+        Some(b'K') | Some(b'k') => (&s[..s.len() - 1], 10),
+        Some(b'M') | Some(b'm') => (&s[..s.len() - 1], 20),
+        _ => (s, 0),
     };
-    println!(
-        "associate client_fd {} backend_fd {}",
-        client_fd, backend_fd
-    );
-    let ctx = Rc::new(RefCell::new(Context::new(client_fd, backend_fd)));
-    {
-        let in_pd = Box::into_raw(Box::new(PollDesp {
-            who: 0,
-            ctx: ctx.clone(),
-        })) as u64;
-        let out_pd = Box::into_raw(Box::new(PollDesp {
-            who: 1,
-            ctx: ctx.clone(),
-        })) as u64;
-        let mut ctx = ctx.borrow_mut();
-        ctx.in_pd = in_pd;
-        ctx.out_pd = out_pd;
-        epoll_add(client_fd, 3, in_pd).unwrap();
-        epoll_add(backend_fd, 3, out_pd).unwrap();
+    num.parse::<isize>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .filter(|&n| n > 0)
+}
+
+// 500ms, 30s, 5m, 1h, a bare number is seconds
+fn duration(value: &str) -> Option<Duration> {
+    let (num, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+    let ms = match unit {
+        "ms" => 1,
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        _ => return None,
+    };
+    match num.parse::<u64>().ok().and_then(|n| n.checked_mul(ms)) {
+        Some(n) if n > 0 => Some(Duration::from_millis(n)),
+        _ => None,
     }
 }
 
-fn main() {
-    {
-        let mut pfd = [0; 2];
-        syscall!(libc::pipe(pfd.as_mut_ptr())).unwrap();
-        syscall!(libc::fcntl(pfd[0], libc::F_GETPIPE_SZ))
-            .map(|n| {
-                unsafe {
-                    PIPE_SIZE_ = n as isize;
-                };
-                ()
-            })
-            .unwrap();
-        unsafe {
-            libc::close(pfd[0]);
-            libc::close(pfd[1]);
+fn parse_duration(name: &str, value: Option<String>) -> Duration {
+    value
+        .and_then(|value| duration(&value))
+        .unwrap_or_else(|| invalid_value(name))
+}
+
+// -b out_size[,in_size], a single size applies to both directions
+fn parse_buf_sizes(name: &str, value: Option<String>) -> (isize, isize) {
+    let value = value.unwrap_or_default();
+    let sizes: Vec<Option<isize>> = value.split(',').map(parse_size).collect();
+    match sizes[..] {
+        [Some(n)] => (n, n),
+        [Some(out_size), Some(in_size)] => (out_size, in_size),
+        _ => invalid_value(name),
+    }
+}
+
+// defaults of -l and -d, a build may bake in its own through the variables
+// of the same name
+const DEFAULT_LISTEN: &str = match option_env!("TCPPROXY_DEFAULT_LISTEN") {
+    Some(value) => value,
+    None => "0.0.0.0:5262",
+};
+const DEFAULT_BACKEND: &str = match option_env!("TCPPROXY_DEFAULT_BACKEND") {
+    Some(value) => value,
+    None => "127.0.0.1:9527",
+};
+
+// host:port1,port2 or host:lo-hi (the two can be mixed) expands to one address per port
+fn parse_listen_addrs(name: &str, value: Option<String>) -> Vec<String> {
+    let value = value.unwrap_or_else(|| invalid_value(name));
+    let (host, ports) = match value.rfind(':') {
+        Some(i) => (&value[..i], &value[i + 1..]),
+        None => invalid_value(name),
+    };
+    if !ports.contains(',') && !ports.contains('-') {
+        return vec![value.clone()];
+    }
+    let mut addrs = Vec::new();
+    for part in ports.split(',') {
+        let range = match part.find('-') {
+            Some(i) => (part[..i].parse::<u16>(), part[i + 1..].parse::<u16>()),
+            None => (part.parse::<u16>(), part.parse::<u16>()),
+        };
+        match range {
+            (Ok(lo), Ok(hi)) if lo <= hi => {
+                addrs.extend((lo..=hi).map(|port| format!("{}:{}", host, port)))
+            }
+            _ => invalid_value(name),
         }
+    }
+    addrs
+}
 
-        println!("pipe size: {}", *PIPE_SIZE);
+// a comma separated list, or @path for a file with one entry per line and
+// # comments
+fn parse_backends(name: &str, value: Option<String>) -> Vec<BackendSpec> {
+    let value = value.unwrap_or_else(|| invalid_value(name));
+    if !value.starts_with('@') {
+        return value
+            .split(',')
+            .map(|entry| BackendSpec::parse(entry.trim(), None))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap_or_else(|_| invalid_value(name));
+    }
+    let path = &value[1..];
+    let content = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        usage();
+    });
+    let mut backends = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let entry = match line.find('#') {
+            Some(j) => &line[..j],
+            None => line,
+        }
+        .trim();
+        if entry.is_empty() {
+            continue;
+        }
+        let origin = format!("{}:{}", path, i + 1);
+        match BackendSpec::parse(entry, Some(origin.clone())) {
+            Ok(backend) => backends.push(backend),
+            Err(e) => {
+                eprintln!("{}: {}", origin, e);
+                usage();
+            }
+        }
+    }
+    if backends.is_empty() {
+        eprintln!("{}: no backends", path);
+        usage();
     }
+    backends
+}
 
-    syscall!(libc::epoll_create1(0))
-        .map(|fd| unsafe {
-            EPOLL_FD_ = fd;
-        })
-        .unwrap();
+// the most -reject-response may send, it has to go out in one
+// nonblocking send
+const MAX_REJECT_RESPONSE: usize = 4096;
 
-    let listen_fd = listen_tcp(&"0.0.0.0:5262".parse().unwrap()).unwrap();
-    epoll_add(listen_fd, 1, 0).unwrap();
+// @file for the bytes of a file, a string with \r, \n, \t and \\ escapes
+fn parse_reject_response(name: &str, value: Option<String>) -> Vec<u8> {
+    let value = value.unwrap_or_else(|| invalid_value(name));
+    let response = match value.strip_prefix('@') {
+        Some(path) => fs::read(path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            usage();
+        }),
+        None => {
+            let mut bytes = Vec::new();
+            let mut chars = value.chars();
+            while let Some(c) = chars.next() {
+                let c = match c {
+                    '\\' => match chars.next() {
+                        Some('r') => '\r',
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('\\') => '\\',
+                        _ => invalid_value(name),
+                    },
+                    c => c,
+                };
+                bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            }
+            bytes
+        }
+    };
+    if response.is_empty() || response.len() > MAX_REJECT_RESPONSE {
+        eprintln!(
+            "{}: {} bytes, 1 to {} allowed",
+            name,
+            response.len(),
+            MAX_REJECT_RESPONSE
+        );
+        usage();
+    }
+    response
+}
 
-    println!("listen ok");
+fn parse_port_range(name: &str, value: Option<String>) -> (u16, u16) {
+    let value = value.unwrap_or_default();
+    let range = match value.find('-') {
+        Some(i) => (value[..i].parse::<u16>(), value[i + 1..].parse::<u16>()),
+        None => invalid_value(name),
+    };
+    match range {
+        (Ok(lo), Ok(hi)) if lo > 0 && lo <= hi => (lo, hi),
+        _ => invalid_value(name),
+    }
+}
 
-    let mut events: [libc::epoll_event; 64] = unsafe { mem::zeroed() };
-    loop {
-        println!("polling events");
-        let res = syscall!(libc::epoll_wait(
-            *EPOLL_FD,
-            events.as_mut_ptr(),
-            events.len() as i32,
-            -1
-        ));
-        let n = match res {
-            Ok(n) => n,
-            Err(e) => {
-                if e == libc::EINTR {
-                    continue;
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Config {
+    let mut config = Config {
+        listen_addrs: Vec::new(),
+        listen_family: Family::Both,
+        tls_listen_addrs: Vec::new(),
+        tls_listen_family: Family::Both,
+        tls_cert: String::new(),
+        tls_key: String::new(),
+        listen_device: String::new(),
+        inherit_fds: Vec::new(),
+        backends: Vec::new(),
+        http_route: false,
+        route_prefix: false,
+        header_max: None,
+        header_timeout: None,
+        routes: Vec::new(),
+        backend_tls: false,
+        backend_sni: String::new(),
+        backend_ca: String::new(),
+        backend_insecure: false,
+        src_addr: None,
+        src_ports: None,
+        mptcp: false,
+        client_sock_opts: Vec::new(),
+        backend_sock_opts: Vec::new(),
+        mark: None,
+        listen_mark: None,
+        md5_key: Vec::new(),
+        socks5: String::new(),
+        socks5_auth: String::new(),
+        http_connect: String::new(),
+        http_connect_auth: String::new(),
+        connlimit: 0,
+        shed_conns: 0,
+        shed_rate: 0,
+        reject_response: Vec::new(),
+        reject_on: Vec::new(),
+        max_events: 64,
+        nofile: 0,
+        connect_timeout: None,
+        idle_timeout: None,
+        read_timeout: None,
+        write_timeout: None,
+        stall_timeout: None,
+        accept_age: None,
+        accept_delay: None,
+        accept_cooldown: ACCEPT_COOLDOWN,
+        runtime: None,
+        drain_timeout: None,
+        sticky: None,
+        in_buf_size: 0,
+        out_buf_size: 0,
+        chunk_size: 0,
+        fair_bytes: 0,
+        lazy: false,
+        retry_reset: false,
+        transparent: false,
+        redirect: false,
+        sink: false,
+        log_json: false,
+        tcp_info: false,
+        mirror: String::new(),
+        control: String::new(),
+        health: String::new(),
+        syslog: String::new(),
+        verbose: false,
+        check: false,
+        print_config: false,
+    };
+    let mut listen_set = false;
+    let mut backend_set = false;
+    // -family applies to the -l or -tls-l before it
+    let mut last_tls = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-l" => {
+                config.listen_addrs = parse_listen_addrs(&arg, args.next());
+                listen_set = true;
+                last_tls = false;
+            }
+            "-inherit-fds" => {
+                let value = args.next().unwrap_or_default();
+                config.inherit_fds = value
+                    .split(',')
+                    .map(|fd| fd.parse::<i32>().ok().filter(|&fd| fd >= 0))
+                    .collect::<Option<_>>()
+                    .unwrap_or_else(|| invalid_value(&arg));
+            }
+            "-tls-l" => {
+                config.tls_listen_addrs = parse_listen_addrs(&arg, args.next());
+                last_tls = true;
+            }
+            "-family" => {
+                let family = parse_value(&arg, args.next());
+                if last_tls {
+                    config.tls_listen_family = family;
+                } else {
+                    config.listen_family = family;
                 }
-                panic!("epoll_wait failed: {}", e);
             }
-        };
-        println!("epoll {} events raised", n);
-        let mut defer_free = Vec::new();
-        for i in 0..n as usize {
-            if events[i].u64 == 0 {
-                loop {
-                    match syscall!(libc::accept4(
-                        listen_fd,
-                        ptr::null_mut(),
-                        ptr::null_mut(),
-                        libc::SOCK_NONBLOCK,
-                    )) {
-                        Ok(fd) => {
-                            println!("accept client_fd: {}", fd);
-                            handle_client(fd);
-                        }
-                        Err(e) => {
-                            if e == libc::EAGAIN {
-                                break;
-                            } else {
-                                panic!("accept failed: {}", e);
-                            }
-                        }
-                    };
+            "-tls-cert" => config.tls_cert = args.next().unwrap_or_else(|| invalid_value(&arg)),
+            "-tls-key" => config.tls_key = args.next().unwrap_or_else(|| invalid_value(&arg)),
+            "-ldev" => {
+                config.listen_device = args
+                    .next()
+                    .filter(|dev| !dev.is_empty() && dev.len() < libc::IFNAMSIZ)
+                    .unwrap_or_else(|| invalid_value(&arg))
+            }
+            "-d" => {
+                config.backends = parse_backends(&arg, args.next());
+                backend_set = true;
+            }
+            "-backend-tls" => config.backend_tls = true,
+            "-backend-sni" => {
+                config.backend_sni = args.next().unwrap_or_else(|| invalid_value(&arg))
+            }
+            "-backend-ca" => config.backend_ca = args.next().unwrap_or_else(|| invalid_value(&arg)),
+            "-backend-insecure" => config.backend_insecure = true,
+            "-srcaddr" => {
+                let addr = args.next().and_then(|value| value.parse().ok());
+                config.src_addr = Some(addr.unwrap_or_else(|| invalid_value(&arg)));
+            }
+            "-srcport" => {
+                // 0 would leave the port to the kernel, as without -srcport
+                let port: u16 = parse_value(&arg, args.next());
+                if port == 0 {
+                    invalid_value(&arg);
                 }
-                continue;
+                config.src_ports = Some((port, port));
             }
-            let pd = unsafe { &mut *(events[i].u64 as *mut PollDesp) };
-            let mut free = false;
-            if events[i].events & (libc::EPOLLIN | libc::EPOLLRDHUP | libc::EPOLLERR) as u32 != 0 {
-                let res = if pd.who == 0 {
-                    pd.ctx.borrow_mut().copy_from()
-                } else {
-                    pd.ctx.borrow_mut().copy_to()
+            "-srcport-range" => config.src_ports = Some(parse_port_range(&arg, args.next())),
+            "-mptcp" => config.mptcp = true,
+            "-mark" => config.mark = Some(parse_value(&arg, args.next())),
+            "-lmark" => config.listen_mark = Some(parse_value(&arg, args.next())),
+            "-md5" => {
+                let value = args.next().unwrap_or_default();
+                let key = match value.strip_prefix('@') {
+                    // a key file may end in a newline
+                    Some(path) => fs::read_to_string(path)
+                        .unwrap_or_else(|e| {
+                            eprintln!("{}: {}", path, e);
+                            usage();
+                        })
+                        .trim_end_matches(['\r', '\n'])
+                        .to_string(),
+                    None => value,
                 };
-                if let Err(e) = res {
-                    println!("copy data failed on IN: {}", e);
-                    free = true;
+                if key.is_empty() || key.len() > libc::TCP_MD5SIG_MAXKEYLEN {
+                    eprintln!("-md5: key of 1 to {} bytes", libc::TCP_MD5SIG_MAXKEYLEN);
+                    usage();
                 }
+                config.md5_key = key.into_bytes();
             }
-            if events[i].events & (libc::EPOLLOUT | libc::EPOLLERR | libc::EPOLLHUP) as u32 != 0 {
-                let res = if pd.who == 1 {
-                    pd.ctx.borrow_mut().copy_from()
-                } else {
-                    pd.ctx.borrow_mut().copy_to()
+            "-sockopt" => {
+                let value = args.next().unwrap_or_default();
+                let (side, opt) = match value.split_once(':') {
+                    Some(("client", opt)) => (Some(true), opt),
+                    Some(("backend", opt)) => (Some(false), opt),
+                    _ => (None, value.as_str()),
                 };
-                if let Err(e) = res {
-                    println!("copy data failed on OUT: {}", e);
-                    free = true;
+                let opt = net::SockOpt::parse(opt).unwrap_or_else(|| invalid_value(&arg));
+                if side != Some(false) {
+                    config.client_sock_opts.push(opt);
+                }
+                if side != Some(true) {
+                    config.backend_sock_opts.push(opt);
+                }
+            }
+            "-http-route" => config.http_route = true,
+            "-route-prefix" => config.route_prefix = true,
+            "-header-max" => {
+                config.header_max = args
+                    .next()
+                    .and_then(|s| parse_size(&s))
+                    .filter(|&n| n > 0 && n as usize <= MAX_HEADER)
+                    .map(|n| n as usize);
+                if config.header_max.is_none() {
+                    invalid_value(&arg);
+                }
+            }
+            "-header-timeout" => config.header_timeout = Some(parse_duration(&arg, args.next())),
+            "-host" => {
+                let value = args.next().unwrap_or_default();
+                let route = value.split_once('=').and_then(|(name, target)| {
+                    let spec = BackendSpec::parse(target.trim(), None).ok()?;
+                    Some((name.trim().to_ascii_lowercase(), spec))
+                });
+                match route {
+                    Some(route) if !route.0.is_empty() => config.routes.push(route),
+                    _ => invalid_value(&arg),
+                }
+            }
+            "-socks5" => config.socks5 = args.next().unwrap_or_else(|| invalid_value(&arg)),
+            "-socks5-auth" => {
+                let auth = args.next().unwrap_or_default();
+                if !auth.contains(':') {
+                    invalid_value(&arg);
+                }
+                config.socks5_auth = auth;
+            }
+            "-http-connect" => {
+                config.http_connect = args.next().unwrap_or_else(|| invalid_value(&arg))
+            }
+            "-http-connect-auth" => {
+                let auth = args.next().unwrap_or_default();
+                if !auth.contains(':') {
+                    invalid_value(&arg);
+                }
+                config.http_connect_auth = auth;
+            }
+            "-connlimit" => config.connlimit = parse_value(&arg, args.next()),
+            "-shed" => config.shed_conns = parse_value(&arg, args.next()),
+            "-shed-rate" => config.shed_rate = parse_value(&arg, args.next()),
+            "-reject-response" => config.reject_response = parse_reject_response(&arg, args.next()),
+            "-reject-on" => {
+                config.reject_on = args
+                    .next()
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|kind| kind.parse().ok())
+                    .collect::<Option<_>>()
+                    .unwrap_or_else(|| invalid_value(&arg));
+            }
+            "-nofile" => config.nofile = parse_value(&arg, args.next()),
+            "-maxevents" => {
+                config.max_events =
+                    parse_max_events(args.next()).unwrap_or_else(|| invalid_value(&arg))
+            }
+            "-ct" => config.connect_timeout = Some(parse_duration(&arg, args.next())),
+            "-timeout" => config.idle_timeout = Some(parse_duration(&arg, args.next())),
+            "-rtimeout" => config.read_timeout = Some(parse_duration(&arg, args.next())),
+            "-wtimeout" => config.write_timeout = Some(parse_duration(&arg, args.next())),
+            "-stall" => config.stall_timeout = Some(parse_duration(&arg, args.next())),
+            "-accept-age" => config.accept_age = Some(parse_duration(&arg, args.next())),
+            "-accept-delay" => config.accept_delay = Some(parse_duration(&arg, args.next())),
+            "-accept-cooldown" => config.accept_cooldown = parse_duration(&arg, args.next()),
+            "-runtime" => config.runtime = Some(parse_duration(&arg, args.next())),
+            "-drain-timeout" => config.drain_timeout = Some(parse_duration(&arg, args.next())),
+            "-sticky" => config.sticky = Some(parse_duration(&arg, args.next())),
+            "-b" => {
+                let (out_size, in_size) = parse_buf_sizes(&arg, args.next());
+                config.out_buf_size = out_size;
+                config.in_buf_size = in_size;
+            }
+            "-chunk" => {
+                config.chunk_size = args
+                    .next()
+                    .and_then(|s| parse_size(&s))
+                    .unwrap_or_else(|| invalid_value(&arg))
+            }
+            "-fairbytes" => {
+                config.fair_bytes = args
+                    .next()
+                    .and_then(|s| parse_size(&s))
+                    .unwrap_or_else(|| invalid_value(&arg))
+            }
+            "-lazy" => config.lazy = true,
+            "-retry-reset" => config.retry_reset = true,
+            "-transparent" => config.transparent = true,
+            "-redirect" => config.redirect = true,
+            "-sink" => config.sink = true,
+            "-log-json" => config.log_json = true,
+            "-tcpinfo" => config.tcp_info = true,
+            "-mirror" => config.mirror = args.next().unwrap_or_else(|| invalid_value(&arg)),
+            "-control" => config.control = args.next().unwrap_or_else(|| invalid_value(&arg)),
+            "-health" => config.health = args.next().unwrap_or_else(|| invalid_value(&arg)),
+            "-syslog" => {
+                config.syslog = args.next().unwrap_or_else(|| invalid_value(&arg));
+                if sys::syslog_facility(&config.syslog).is_none() {
+                    invalid_value(&arg);
                 }
             }
-            if free {
-                defer_free.push(pd.ctx.clone());
+            "-v" => config.verbose = true,
+            "--check" => config.check = true,
+            "--print-config" => config.print_config = true,
+            _ => usage(),
+        }
+    }
+    if !config.tls_listen_addrs.is_empty()
+        && (config.tls_cert.is_empty() || config.tls_key.is_empty())
+    {
+        eprintln!("-tls-l requires -tls-cert and -tls-key");
+        usage();
+    }
+    if !config.routes.is_empty() && !config.http_route && !config.route_prefix {
+        eprintln!("-host requires -http-route or -route-prefix");
+        usage();
+    }
+    if config.route_prefix && config.routes.is_empty() {
+        eprintln!("-route-prefix requires -host");
+        usage();
+    }
+    if config.route_prefix && (config.http_route || !config.tls_listen_addrs.is_empty()) {
+        eprintln!("-route-prefix excludes -http-route and -tls-l");
+        usage();
+    }
+    if config.drain_timeout.is_some() && config.runtime.is_none() {
+        eprintln!("-drain-timeout requires -runtime");
+        usage();
+    }
+    if !config.reject_on.is_empty() && config.reject_response.is_empty() {
+        eprintln!("-reject-on requires -reject-response");
+        usage();
+    }
+    if (config.header_max.is_some() || config.header_timeout.is_some())
+        && !config.http_route
+        && !config.route_prefix
+    {
+        eprintln!("-header-max and -header-timeout require -http-route or -route-prefix");
+        usage();
+    }
+    if !config.socks5_auth.is_empty() && config.socks5.is_empty() {
+        eprintln!("-socks5-auth requires -socks5");
+        usage();
+    }
+    if !config.http_connect_auth.is_empty() && config.http_connect.is_empty() {
+        eprintln!("-http-connect-auth requires -http-connect");
+        usage();
+    }
+    if config.sink && !config.tls_listen_addrs.is_empty() {
+        eprintln!("-sink and -tls-l are exclusive");
+        usage();
+    }
+    if !config.socks5.is_empty() && !config.http_connect.is_empty() {
+        eprintln!("-socks5 and -http-connect are exclusive");
+        usage();
+    }
+    if let Some(mark) = config.mark {
+        config.backend_sock_opts.push(net::SockOpt::mark(mark));
+    }
+    // the upstream proxy would get the signature, not the backend
+    if !config.md5_key.is_empty() && (config.mptcp || config.upstream().is_some()) {
+        eprintln!("-md5 excludes -mptcp, -socks5 and -http-connect");
+        usage();
+    }
+    if config.transparent
+        && (config.http_route
+            || config.route_prefix
+            || config.upstream().is_some()
+            || config.backend_tls
+            || config.src_addr.is_some()
+            || config.src_ports.is_some()
+            || config.mptcp
+            || config.sink)
+    {
+        eprintln!("-transparent excludes -http-route, -route-prefix, -socks5, -http-connect, -backend-tls, -srcaddr, -srcport, -mptcp and -sink");
+        usage();
+    }
+    if config.redirect
+        && (config.transparent
+            || config.http_route
+            || config.route_prefix
+            || config.upstream().is_some()
+            || config.backend_tls
+            || config.sink)
+    {
+        eprintln!("-redirect excludes -transparent, -http-route, -route-prefix, -socks5, -http-connect, -backend-tls and -sink");
+        usage();
+    }
+    if let Some(addr) = config.src_addr {
+        if addr.port() != 0 {
+            if config.src_ports.is_some() {
+                eprintln!("-srcaddr with a port excludes -srcport and -srcport-range");
+                usage();
             }
+            config.src_ports = Some((addr.port(), addr.port()));
         }
-        for v in defer_free {
-            let mut ctx = v.borrow_mut();
-            ctx.shutdown();
+    }
+    if !config.backend_tls
+        && (!config.backend_sni.is_empty()
+            || !config.backend_ca.is_empty()
+            || config.backend_insecure)
+    {
+        eprintln!("-backend-sni, -backend-ca and -backend-insecure require -backend-tls");
+        usage();
+    }
+    // the environment only fills in what the command line left out, the
+    // defaults what both did
+    if !listen_set {
+        if let Ok(value) = env::var("TCPPROXY_LISTEN") {
+            config.listen_addrs = parse_listen_addrs("TCPPROXY_LISTEN", Some(value));
+        } else if config.inherit_fds.is_empty() && config.tls_listen_addrs.is_empty() {
+            // inherited or -tls-l listeners take the place of the default one
+            config.listen_addrs =
+                parse_listen_addrs("TCPPROXY_DEFAULT_LISTEN", Some(DEFAULT_LISTEN.to_string()));
         }
     }
+    if !backend_set {
+        let value = env::var("TCPPROXY_BACKEND");
+        config.backends = match value {
+            Ok(value) => parse_backends("TCPPROXY_BACKEND", Some(value)),
+            Err(_) => parse_backends(
+                "TCPPROXY_DEFAULT_BACKEND",
+                Some(DEFAULT_BACKEND.to_string()),
+            ),
+        };
+    }
+    config
+}
+
+// the backend of -transparent and -redirect, each connection goes where its
+// client was headed
+const ORIGINAL_DST: &str = "original destination";
+
+fn original_dst_backend(config: &Config) -> Backend {
+    Backend {
+        name: ORIGINAL_DST.to_string(),
+        host: String::new(),
+        port: 0,
+        addr: None,
+        weight: 1,
+        current: 0,
+        server_name: None,
+        connect_timeout: config.connect_timeout,
+    }
+}
+
+// the name presented to a TLS backend is its host unless -backend-sni
+// overrides it
+fn resolve_backends(config: &Config) -> Result<Vec<Backend>, String> {
+    // -transparent leaves -d aside
+    if config.transparent {
+        return Ok(vec![original_dst_backend(config)]);
+    }
+    let mut backends = Vec::new();
+    let routes = config.routes.iter().map(|(_, spec)| spec);
+    for spec in config.backends.iter().chain(routes) {
+        // behind an upstream proxy the names are resolved by the proxy
+        let addr = if config.upstream().is_none() {
+            Some(net::resolve_first(&spec.addr, false).map_err(|e| spec.error(e))?)
+        } else {
+            None
+        };
+        if let (Some(src), Some(addr)) = (config.src_addr, addr) {
+            if src.is_ipv4() != addr.is_ipv4() {
+                return Err(spec.error(format!("-srcaddr {} cannot reach {}", src.ip(), addr)));
+            }
+        }
+        let (host, port) = net::split_host_port(&spec.addr)?;
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| spec.error(format!("numeric port required in {}", spec.addr)))?;
+        let server_name = if !config.backend_tls {
+            None
+        } else if config.backend_sni.is_empty() {
+            Some(tls::server_name(host).map_err(|e| spec.error(e))?)
+        } else {
+            Some(tls::server_name(&config.backend_sni)?)
+        };
+        backends.push(Backend {
+            name: spec.addr.clone(),
+            host: host.to_string(),
+            port,
+            addr,
+            weight: i64::from(spec.weight),
+            current: 0,
+            server_name,
+            connect_timeout: spec.connect_timeout.or(config.connect_timeout),
+        });
+    }
+    // -redirect falls back to the -d backends for clients that were not
+    // redirected
+    if config.redirect {
+        backends.push(original_dst_backend(config));
+    }
+    Ok(backends)
+}
+
+const CAP_NET_BIND_SERVICE: u32 = 10;
+
+// ports below ip_unprivileged_port_start need root or CAP_NET_BIND_SERVICE
+fn can_bind_port(port: u16) -> bool {
+    if unsafe { libc::geteuid() } == 0 {
+        return true;
+    }
+    let start = fs::read_to_string("/proc/sys/net/ipv4/ip_unprivileged_port_start")
+        .ok()
+        .and_then(|s| s.trim().parse::<u32>().ok())
+        .unwrap_or(1024);
+    if u32::from(port) >= start {
+        return true;
+    }
+    fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find(|line| line.starts_with("CapEff:"))
+                .and_then(|line| u64::from_str_radix(line[7..].trim(), 16).ok())
+        })
+        .is_some_and(|caps| caps & (1 << CAP_NET_BIND_SERVICE) != 0)
+}
+
+// prints the effective configuration for --check and --print-config. With
+// validate, what the start would fail on is checked as well: ports and
+// privileges, the TLS files, the device and the inherited fds
+fn check_config(config: &Config, validate: bool) -> Result<(), String> {
+    let mut listen_addrs = Vec::new();
+    for (addr, _, _) in config.listeners() {
+        let listen_addr = net::resolve_first(addr, true)?;
+        if validate && !can_bind_port(listen_addr.port()) {
+            return Err(format!(
+                "listen {}: port {} requires root or CAP_NET_BIND_SERVICE",
+                addr,
+                listen_addr.port()
+            ));
+        }
+        listen_addrs.push(listen_addr);
+    }
+    if validate && !config.tls_listen_addrs.is_empty() {
+        tls::load_server_config(&config.tls_cert, &config.tls_key)?;
+    }
+    if let Some(device) = config.listen_device().filter(|_| validate) {
+        fs::metadata(format!("/sys/class/net/{}", device))
+            .map_err(|_| format!("-ldev {}: no such device", device))?;
+    }
+    let backends = resolve_backends(config)?;
+    if validate && config.backend_tls {
+        tls::load_client_config(&config.backend_ca, config.backend_insecure)?;
+    }
+    for ((addr, tls, family), listen_addr) in config.listeners().into_iter().zip(&listen_addrs) {
+        let kind = if tls { "tls listen" } else { "listen" };
+        if family == Family::Both {
+            println!("{} {} ({})", kind, addr, listen_addr);
+        } else {
+            println!("{} {} ({}) {} only", kind, addr, listen_addr, family.name());
+        }
+    }
+    if !config.tls_listen_addrs.is_empty() {
+        println!("tls cert {} key {}", config.tls_cert, config.tls_key);
+    }
+    if validate {
+        for (fd, addr) in adopt_listen_fds(&config.inherit_fds)? {
+            println!("inherited fd {} listening on {}", fd, addr);
+        }
+    } else {
+        for fd in &config.inherit_fds {
+            println!("inherited fd {}", fd);
+        }
+    }
+    if let Some(device) = config.listen_device() {
+        println!("listen device {}", device);
+    }
+    let describe = |spec: &BackendSpec, backend: &Backend| {
+        let mut line = match backend.addr {
+            Some(addr) => format!("backend {} ({})", spec.addr, addr),
+            None => format!("backend {}", spec.addr),
+        };
+        if spec.weight != 1 {
+            line += &format!(" weight {}", spec.weight);
+        }
+        if let Some(timeout) = spec.connect_timeout {
+            line += &format!(" connect timeout {:?}", timeout);
+        }
+        if let Some(ref name) = backend.server_name {
+            line += &format!(" tls {}", name.to_str());
+        }
+        line
+    };
+    if config.transparent {
+        println!("transparent, to the original destination from the client address");
+    } else {
+        for (spec, backend) in config.backends.iter().zip(&backends) {
+            println!("{}", describe(spec, backend));
+        }
+    }
+    if config.redirect {
+        println!("redirect, to the original destination, the backends above for others");
+    }
+    if config.http_route {
+        println!("http routing by host");
+    }
+    if config.route_prefix {
+        println!("routing by the ROUTE: line clients send first");
+    }
+    if config.http_route || config.route_prefix {
+        match config.header_timeout {
+            Some(timeout) => println!(
+                "header max {} bytes, timeout {:?}",
+                config.header_max(),
+                timeout
+            ),
+            None => println!("header max {} bytes", config.header_max()),
+        }
+    }
+    let route_backends = &backends[backends.len() - config.routes.len()..];
+    for ((name, spec), backend) in config.routes.iter().zip(route_backends) {
+        println!("route {} to {}", name, describe(spec, backend));
+    }
+    if config.backend_tls {
+        let verify = if config.backend_insecure {
+            "unverified".to_string()
+        } else if config.backend_ca.is_empty() {
+            "system CAs".to_string()
+        } else {
+            config.backend_ca.clone()
+        };
+        println!("backend tls verify {}", verify);
+    }
+    if !config.socks5.is_empty() {
+        let socks5 = net::resolve_first(&config.socks5, false)?;
+        println!("via socks5 {} ({})", config.socks5, socks5);
+    }
+    if !config.http_connect.is_empty() {
+        let proxy = net::resolve_first(&config.http_connect, false)?;
+        println!("via http connect {} ({})", config.http_connect, proxy);
+    }
+    if let Some(addr) = config.src_addr {
+        println!("source address {}", addr.ip());
+    }
+    if let Some((lo, hi)) = config.src_ports {
+        println!("source ports {}-{}", lo, hi);
+    }
+    if config.mptcp {
+        match net::mptcp_available() {
+            Ok(()) => println!("mptcp to backends"),
+            Err(e) => println!("mptcp to backends unavailable ({}), plain tcp", e),
+        }
+    }
+    if validate {
+        check_marks(config)?;
+    }
+    if let Some(mark) = config.listen_mark {
+        println!("listener mark {}", mark);
+    }
+    if !config.md5_key.is_empty() {
+        if validate {
+            net::md5sig_available().map_err(md5_unavailable)?;
+        }
+        println!("tcp md5 signatures to backends");
+    }
+    for (side, opts) in &[
+        ("client", &config.client_sock_opts),
+        ("backend", &config.backend_sock_opts),
+    ] {
+        for opt in opts.iter() {
+            println!("{} sockopt {}", side, opt);
+        }
+    }
+    if config.connlimit != 0 {
+        println!("connlimit {}", config.connlimit);
+    }
+    if config.shed_conns != 0 {
+        println!("shed clients above {} connections", config.shed_conns);
+    }
+    if config.shed_rate != 0 {
+        println!("shed clients above {} accepts/s", config.shed_rate);
+    }
+    if !config.reject_response.is_empty() {
+        let on = if config.reject_on.is_empty() {
+            "any reject".to_string()
+        } else {
+            let kinds: Vec<_> = config.reject_on.iter().map(|r| r.name()).collect();
+            kinds.join(",")
+        };
+        println!(
+            "reject response of {} bytes on {}",
+            config.reject_response.len(),
+            on
+        );
+    }
+    if config.max_events != 64 {
+        println!("max events {}", config.max_events);
+    }
+    let (soft, hard) = sys::nofile().map_err(|e| format!("getrlimit failed: {}", e))?;
+    if config.nofile != 0 {
+        println!(
+            "fd limit {} of hard {}, raised to {}",
+            soft,
+            hard,
+            config.nofile.min(hard)
+        );
+    } else {
+        println!("fd limit {} of hard {}", soft, hard);
+    }
+    for (name, timeout) in &[
+        ("connect", config.connect_timeout),
+        ("idle", config.idle_timeout),
+        ("read idle", config.read_timeout),
+        ("write idle", config.write_timeout),
+        ("stall", config.stall_timeout),
+    ] {
+        if let Some(timeout) = *timeout {
+            println!("{} timeout {:?}", name, timeout);
+        }
+    }
+    if let Some(ttl) = config.sticky {
+        println!("sticky client ips for {:?}", ttl);
+    }
+    if let Some(age) = config.accept_age {
+        println!("max accept queue age {:?}", age);
+    }
+    if let Some(delay) = config.accept_delay {
+        println!("accept delay {:?}, for testing only", delay);
+    }
+    if config.accept_cooldown != ACCEPT_COOLDOWN {
+        println!(
+            "accept cooldown {:?} when out of fds",
+            config.accept_cooldown
+        );
+    }
+    if let Some(runtime) = config.runtime {
+        println!(
+            "runtime {:?}, then drain for up to {:?} and exit",
+            runtime,
+            config.drain_timeout()
+        );
+    }
+    if config.out_buf_size != 0 {
+        println!(
+            "buffers out {} in {}",
+            config.out_buf_size, config.in_buf_size
+        );
+    }
+    if config.chunk_size != 0 {
+        println!("splice chunk {}", config.chunk_size);
+    }
+    if config.fair_bytes != 0 {
+        println!("fair bytes {}", config.fair_bytes);
+    }
+    if config.lazy {
+        println!("lazy backend connect");
+    }
+    if config.retry_reset {
+        println!("retry on a backend reset before any data went through");
+    }
+    if config.sink {
+        println!("sink, client data is discarded and no backend connected");
+    }
+    if config.log_json {
+        println!("json connection log");
+    }
+    if config.tcp_info {
+        println!("tcp_info wire bytes");
+    }
+    if !config.mirror.is_empty() {
+        let mirror = net::resolve_first(&config.mirror, false)?;
+        println!("mirror to {} ({})", config.mirror, mirror);
+    }
+    if !config.control.is_empty() {
+        println!("control socket {}", config.control);
+    }
+    if !config.health.is_empty() {
+        let health = net::resolve_first(&config.health, true)?;
+        println!("health check on {} ({})", config.health, health);
+    }
+    if !config.syslog.is_empty() {
+        println!("log to syslog facility {}", config.syslog);
+    }
+    if config.verbose {
+        println!("verbose log");
+    }
+    Ok(())
+}
+
+// splice calls per direction the teardown spends on what is left in a pipe
+const FINAL_SPLICES: usize = 4;
+
+struct IoBuf {
+    pfd: [i32; 2],
+    size: isize,
+    // cap of a single splice call, at most size
+    chunk: isize,
+    buffered: isize,
+    // bytes that left the pipe towards the destination
+    moved: u64,
+    // -fairbytes, what one copy moves before the connection yields, 0 no cap
+    fair: u64,
+    // the last copy stopped on the cap with the source possibly not drained
+    yielded: bool,
+    // copies that ended with the pipe full, the destination not keeping up
+    full: u64,
+    // -mirror, gets a copy of what is spliced out
+    tap: Option<Tap>,
+}
+
+impl IoBuf {
+    // size 0 keeps the probed default pipe size, chunk 0 splices up to the
+    // whole pipe at once
+    fn new(size: isize, chunk: isize) -> SysResult<IoBuf> {
+        let mut pfd = [0; 2];
+        syscall!(libc::pipe2(
+            pfd.as_mut_ptr(),
+            libc::O_NONBLOCK | libc::O_CLOEXEC
+        ))?;
+        let size = if size > 0 {
+            match syscall!(libc::fcntl(pfd[1], libc::F_SETPIPE_SZ, size as libc::c_int)) {
+                Ok(n) => n as isize,
+                Err(e) => {
+                    log!("set pipe size {} failed: {}", size, e);
+                    pipe_size()
+                }
+            }
+        } else {
+            pipe_size()
+        };
+        Ok(IoBuf {
+            pfd,
+            size,
+            chunk: if chunk > 0 { chunk.min(size) } else { size },
+            buffered: 0,
+            moved: 0,
+            fair: 0,
+            yielded: false,
+            full: 0,
+            tap: None,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buffered == 0
+    }
+
+    // bytes that came in from the source so far
+    fn received(&self) -> u64 {
+        self.moved + self.buffered as u64
+    }
+
+    fn splice_in(&mut self, fd: i32) -> SysResult<bool> {
+        let max_size = self.size;
+        while self.buffered < max_size {
+            let r = syscall!(libc::splice(
+                fd,
+                ptr::null_mut(),
+                self.pfd[1],
+                ptr::null_mut(),
+                (max_size - self.buffered).min(self.chunk) as usize,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK
+            ));
+            let n = match r {
+                Ok(n) => n,
+                Err(e) => {
+                    if e == libc::EAGAIN {
+                        break;
+                    }
+                    return Err(e);
+                }
+            };
+            if n == 0 {
+                // splice also returns 0 when it moved nothing for reasons other
+                // than EOF, only a peek that reads 0 proves the peer closed;
+                // data behind a 0 means splice again, EAGAIN waits for the next edge
+                match net::peek(fd) {
+                    Ok(0) => return Ok(true),
+                    Ok(_) => continue,
+                    Err(e) if e == libc::EAGAIN => break,
+                    // not a socket, nothing better than splice to go by
+                    Err(e) if e == libc::ENOTSOCK => return Ok(true),
+                    Err(e) => return Err(e),
+                }
+            }
+            self.buffered += n;
+        }
+        Ok(false)
+    }
+
+    fn splice_out(&mut self, fd: i32) -> SysResult<()> {
+        self.splice_out_at_most(fd, usize::MAX).map(|_| ())
+    }
+
+    // true when it stopped after calls splices with data left, the
+    // destination still taking it
+    fn splice_out_at_most(&mut self, fd: i32, calls: usize) -> SysResult<bool> {
+        for _ in 0..calls {
+            if self.buffered == 0 {
+                return Ok(false);
+            }
+            let mut len = self.buffered.min(self.chunk);
+            // only what the tap got a copy of leaves the pipe
+            if let Some(ref mut tap) = self.tap {
+                match tap.tee(self.pfd[0], len) {
+                    Ok(n) => len = n,
+                    Err(e) => {
+                        log!("mirror dropped: {}", e);
+                        self.tap = None;
+                    }
+                }
+            }
+            let r = syscall!(libc::splice(
+                self.pfd[0],
+                ptr::null_mut(),
+                fd,
+                ptr::null_mut(),
+                len as usize,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK
+            ));
+            let n = match r {
+                Ok(n) => n,
+                Err(e) => {
+                    if e == libc::EAGAIN {
+                        return Ok(false);
+                    }
+                    return Err(e);
+                }
+            };
+            self.buffered -= n;
+            self.moved += n as u64;
+            if let Some(ref mut tap) = self.tap {
+                tap.ahead -= n;
+            }
+        }
+        Ok(self.buffered > 0)
+    }
+
+    // empties the pipe for a destination that is gone, the bytes dropped do
+    // not count as moved
+    fn discard(&mut self) -> SysResult<usize> {
+        let mut scratch = [0u8; 4096];
+        let mut dropped = 0;
+        while self.buffered > 0 {
+            let len = scratch.len().min(self.buffered as usize);
+            let n = syscall!(libc::read(self.pfd[0], scratch.as_mut_ptr() as *mut _, len))?;
+            if n == 0 {
+                break;
+            }
+            self.buffered -= n;
+            dropped += n as usize;
+        }
+        // none of them was forwarded, the mirror keeps the copy it may have
+        // of the first and never sees the rest
+        if let Some(ref mut tap) = self.tap {
+            tap.ahead = 0;
+        }
+        Ok(dropped)
+    }
+
+    // plain copies for the buffered paths, 0 when the pipe is full or empty
+    fn write(&mut self, data: &[u8]) -> SysResult<usize> {
+        let r = syscall!(libc::write(
+            self.pfd[1],
+            data.as_ptr() as *const _,
+            data.len()
+        ));
+        match r {
+            Ok(n) => {
+                self.buffered += n;
+                Ok(n as usize)
+            }
+            Err(e) if e == libc::EAGAIN => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read(&mut self, data: &mut [u8]) -> SysResult<usize> {
+        let len = data.len().min(self.buffered as usize);
+        let r = syscall!(libc::read(self.pfd[0], data.as_mut_ptr() as *mut _, len));
+        match r {
+            Ok(n) => {
+                self.buffered -= n;
+                self.moved += n as u64;
+                Ok(n as usize)
+            }
+            Err(e) if e == libc::EAGAIN => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Drop for IoBuf {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.pfd[0]);
+            libc::close(self.pfd[1]);
+        }
+    }
+}
+
+// a connection of its own to the -mirror address. Teed data waits in a
+// second pipe, a tap that falls behind until that is full misses what comes
+// meanwhile rather than holding up the connection
+struct Tap {
+    fd: i32,
+    pfd: [i32; 2],
+    buffered: isize,
+    // bytes at the head of the buffer pipe teed or skipped already
+    ahead: isize,
+    // bytes that went out without a copy while the pipe was full
+    skipped: u64,
+}
+
+impl Tap {
+    fn connect(addr: &SocketAddr) -> SysResult<Tap> {
+        let fd = net::connect_tcp(addr, None, None, false, &[], None)?;
+        let mut pfd = [0; 2];
+        if let Err(e) = syscall!(libc::pipe2(
+            pfd.as_mut_ptr(),
+            libc::O_NONBLOCK | libc::O_CLOEXEC
+        )) {
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+        Ok(Tap {
+            fd,
+            pfd,
+            buffered: 0,
+            ahead: 0,
+            skipped: 0,
+        })
+    }
+
+    // how much of the head of the buffer pipe, at most len, the tap has a
+    // copy of
+    fn tee(&mut self, from: i32, len: isize) -> SysResult<isize> {
+        if self.ahead == 0 {
+            self.flush()?;
+            let r = syscall!(libc::tee(
+                from,
+                self.pfd[1],
+                len as usize,
+                libc::SPLICE_F_NONBLOCK
+            ));
+            match r {
+                Ok(n) => {
+                    self.ahead = n;
+                    self.buffered += n;
+                    self.flush()?;
+                }
+                // full, the chunk leaves without a copy
+                Err(e) if e == libc::EAGAIN => {
+                    self.ahead = len;
+                    self.skipped += len as u64;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.ahead.min(len))
+    }
+
+    fn flush(&mut self) -> SysResult<()> {
+        while self.buffered > 0 {
+            let r = syscall!(libc::splice(
+                self.pfd[0],
+                ptr::null_mut(),
+                self.fd,
+                ptr::null_mut(),
+                self.buffered as usize,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK
+            ));
+            match r {
+                Ok(n) => self.buffered -= n,
+                Err(e) if e == libc::EAGAIN => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Tap {
+    fn drop(&mut self) {
+        // what is left goes out if it fits right away
+        let _ = self.flush();
+        unsafe {
+            libc::close(self.fd);
+            libc::close(self.pfd[0]);
+            libc::close(self.pfd[1]);
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum CopyMode {
+    // both ends spliced, the data never enters user space
+    Splice,
+    // a TLS end copies through a user space buffer
+    Buffered,
+}
+
+impl CopyMode {
+    fn name(self) -> &'static str {
+        match self {
+            CopyMode::Splice => "splice",
+            CopyMode::Buffered => "buffered",
+        }
+    }
+}
+
+// Connecting -> Streaming once the backend connect completes, through
+// Handshake when the backend is reached via an upstream proxy. When one
+// direction hit EOF and drained, its destination gets SHUT_WR and the context
+// moves to HalfClosedIn (client->backend done) or HalfClosedOut
+// (backend->client done); the second direction finishing, or any error,
+// moves it to Closing, where it waits for the teardown at the end of the
+// event batch. REBIND on the control socket takes a Streaming context through
+// Rebinding back to Connecting, on a new backend.
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    // waiting for the backend, which may not even be dialed yet (lazy, queued)
+    Connecting,
+    // connected to an upstream proxy, setting up the tunnel to the backend
+    Handshake,
+    Streaming,
+    // the old backend gets the buffered client data and SHUT_WR, its reply is
+    // forwarded up to its EOF, then the new backend is dialed
+    Rebinding,
+    HalfClosedIn,
+    HalfClosedOut,
+    Closing,
+}
+
+impl State {
+    fn name(self) -> &'static str {
+        match self {
+            State::Connecting => "connecting",
+            State::Handshake => "handshake",
+            State::Streaming => "streaming",
+            State::Rebinding => "rebinding",
+            State::HalfClosedIn => "half_closed_in",
+            State::HalfClosedOut => "half_closed_out",
+            State::Closing => "closing",
+        }
+    }
+}
+
+struct Rebind {
+    // index into Global::backends
+    backend: usize,
+    // SHUT_WR went out to the old backend
+    shut: bool,
+    // the old backend is drained, the swap happens at the end of the batch
+    ready: bool,
+}
+
+// why a connection was torn down, picked where the teardown starts
+#[derive(Clone, Copy)]
+enum CloseReason {
+    // both directions finished, the client closed its side first
+    ClientEof,
+    // both directions finished, the backend closed its side first
+    BackendEof,
+    ConnectFailed(i32),
+    // the backend reset the connection while data was being copied
+    BackendReset,
+    // no data within -timeout, -rtimeout or -wtimeout
+    IdleTimeout,
+    // a destination took none of what was waiting for it within -stall
+    Stalled,
+    // a routed client did not complete its header within -header-timeout
+    HeaderTimeout,
+    // still open when the -drain-timeout after -runtime was up
+    DrainTimeout,
+    // a socket or TLS error while copying
+    Error(i32),
+}
+
+impl CloseReason {
+    fn name(self) -> &'static str {
+        match self {
+            CloseReason::ClientEof => "client_eof",
+            CloseReason::BackendEof => "backend_eof",
+            CloseReason::ConnectFailed(_) => "connect_failed",
+            CloseReason::BackendReset => "backend_reset",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::Stalled => "stalled",
+            CloseReason::HeaderTimeout => "header_timeout",
+            CloseReason::DrainTimeout => "drain_timeout",
+            CloseReason::Error(_) => "error",
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CloseReason::ConnectFailed(e) | CloseReason::Error(e) => {
+                write!(f, "{} {}", self.name(), e)
+            }
+            _ => f.write_str(self.name()),
+        }
+    }
+}
+
+impl From<i32> for CloseReason {
+    fn from(e: i32) -> CloseReason {
+        CloseReason::Error(e)
+    }
+}
+
+struct Context {
+    state: State,
+    queued: bool,
+    client_fd: i32,
+    backend_fd: i32,
+    in_buf: IoBuf,
+    out_buf: IoBuf,
+    client_tls: Option<Box<tls::TlsStream>>,
+    backend_tls: Option<Box<tls::TlsStream>>,
+    in_pd: u64,
+    out_pd: u64,
+    connect_start: Instant,
+    start: Instant,
+    // last time data came in from the client and from the backend
+    last_in: Instant,
+    last_out: Instant,
+    // since when the pipe of each direction is stuck, the last time it was
+    // empty or its destination took some of it, directly or out of the
+    // socket buffer by the bytes it acked (backend, client)
+    drained_in: Instant,
+    drained_out: Instant,
+    acked: (u64, u64),
+    // when the first byte went out to the backend and to the client
+    first_in: Option<Instant>,
+    first_out: Option<Instant>,
+    // numbers the connections in the order they came in, unlike the fds it
+    // is never reused
+    id: u64,
+    client_addr: Option<SocketAddr>,
+    // the address of the listener it came in on
+    listener: Option<SocketAddr>,
+    // with -transparent or -redirect where the client was headed, the
+    // backend it gets
+    dest: Option<SocketAddr>,
+    // index into Global::backends once one was picked
+    backend: Option<usize>,
+    // bytes moved while an earlier backend was connected, counted for that one
+    credited: (u64, u64),
+    handshake: Option<Box<upstream::Handshake>>,
+    close_reason: Option<CloseReason>,
+    // TCP_INFO sample taken at teardown with -tcpinfo
+    wire: Option<stats::Wire>,
+    rebind: Option<Rebind>,
+    // -http-route holds the client back until its request head is in
+    routing: bool,
+    // waiting in the ready queue of the event loop
+    ready: bool,
+    // taken out of epoll by the PAUSE control command
+    paused: bool,
+    // moved to another backend after a reset with -retry-reset
+    retried: bool,
+}
+
+impl Context {
+    fn new(
+        client_fd: i32,
+        client_addr: Option<SocketAddr>,
+        client_tls: Option<Box<tls::TlsStream>>,
+        in_size: isize,
+        out_size: isize,
+        chunk: isize,
+        fair: isize,
+    ) -> SysResult<Context> {
+        let mut in_buf = IoBuf::new(in_size, chunk)?;
+        let mut out_buf = IoBuf::new(out_size, chunk)?;
+        in_buf.fair = fair as u64;
+        out_buf.fair = fair as u64;
+        Ok(Context {
+            state: State::Connecting,
+            queued: false,
+            client_fd,
+            backend_fd: -1,
+            in_buf,
+            out_buf,
+            client_tls,
+            backend_tls: None,
+            in_pd: 0,
+            out_pd: 0,
+            connect_start: Instant::now(),
+            start: Instant::now(),
+            last_in: Instant::now(),
+            last_out: Instant::now(),
+            drained_in: Instant::now(),
+            drained_out: Instant::now(),
+            first_in: None,
+            first_out: None,
+            acked: (0, 0),
+            id: 0,
+            client_addr,
+            listener: None,
+            dest: None,
+            backend: None,
+            credited: (0, 0),
+            handshake: None,
+            close_reason: None,
+            wire: None,
+            rebind: None,
+            routing: false,
+            ready: false,
+            paused: false,
+            retried: false,
+        })
+    }
+
+    // a TLS side replaces the splice on that end with the rustls path, true
+    // once the source hit EOF and everything, close_notify included, is out
+    fn copy(
+        buf: &mut IoBuf,
+        from_fd: i32,
+        mut from_tls: Option<&mut tls::TlsStream>,
+        to_fd: i32,
+        mut to_tls: Option<&mut tls::TlsStream>,
+        writable: bool,
+    ) -> SysResult<bool> {
+        let start = buf.moved;
+        buf.yielded = false;
+        loop {
+            let eof = match from_tls {
+                Some(ref mut tls) => tls.read_into(from_fd, buf)?,
+                None => buf.splice_in(from_fd)?,
+            };
+            let moved = buf.moved;
+            if writable {
+                match to_tls {
+                    Some(ref mut tls) => tls.write_from(buf, to_fd)?,
+                    None if !buf.is_empty() => buf.splice_out(to_fd)?,
+                    None => {}
+                }
+            }
+            if eof && buf.is_empty() {
+                return match to_tls {
+                    Some(ref mut tls) if writable => tls.shutdown(to_fd),
+                    _ => Ok(true),
+                };
+            }
+            // the read may have stopped on a full pipe rather than a drained
+            // source, and no further edge would announce the data left behind.
+            // Spliced pages can use up the pipe slots well before size bytes
+            // are buffered, so go again whenever the write made room
+            if buf.moved == moved {
+                if buf.buffered == buf.size {
+                    buf.full += 1;
+                    debug!("pipe full towards fd {}", to_fd);
+                }
+                return Ok(false);
+            }
+            // no edge comes for what is left, the loop gives it another turn
+            if buf.fair > 0 && buf.moved - start >= buf.fair {
+                buf.yielded = true;
+                return Ok(false);
+            }
+        }
+    }
+
+    fn copy_mode(&self) -> CopyMode {
+        if self.client_tls.is_some() || self.backend_tls.is_some() {
+            CopyMode::Buffered
+        } else {
+            CopyMode::Splice
+        }
+    }
+
+    fn has_backend(&self) -> bool {
+        match self.state {
+            State::Connecting | State::Handshake => false,
+            _ => self.backend_fd >= 0,
+        }
+    }
+
+    fn connect_in_flight(&self) -> bool {
+        self.backend_fd >= 0 && self.state == State::Connecting
+    }
+
+    // Err once the connection is over, both directions done or broken
+    fn copy_from(&mut self) -> Result<(), CloseReason> {
+        match self.state {
+            State::HalfClosedIn | State::Closing => Ok(()),
+            // the head is peeked at and has to stay in the socket
+            State::Connecting if self.routing => Ok(()),
+            State::Rebinding => Ok(self.drain_for_rebind()?),
+            state => {
+                // buffer client data until the backend is connected
+                let writable = self.has_backend();
+                let received = self.in_buf.received();
+                let (moved, empty) = (self.in_buf.moved, self.in_buf.is_empty());
+                let res = Context::copy(
+                    &mut self.in_buf,
+                    self.client_fd,
+                    self.client_tls.as_deref_mut(),
+                    self.backend_fd,
+                    self.backend_tls.as_deref_mut(),
+                    writable,
+                );
+                if self.in_buf.received() != received {
+                    self.last_in = Instant::now();
+                }
+                if self.in_buf.moved != moved || empty {
+                    self.drained_in = Instant::now();
+                }
+                if self.in_buf.moved != moved && self.first_in.is_none() {
+                    self.first_in = Some(self.drained_in);
+                }
+                let eof = match res {
+                    Err(e) if e == libc::EPIPE && self.dest_gone(state, true)? => return Ok(()),
+                    res => res.map_err(|e| self.copy_error(e))?,
+                };
+                if !eof {
+                    return Ok(());
+                }
+                // the client left before anything had to be forwarded
+                if state == State::Connecting || state == State::Handshake {
+                    return Err(CloseReason::ClientEof);
+                }
+                syscall!(libc::shutdown(self.backend_fd, libc::SHUT_WR))?;
+                if state == State::HalfClosedOut {
+                    return Err(CloseReason::BackendEof);
+                }
+                self.state = State::HalfClosedIn;
+                Ok(())
+            }
+        }
+    }
+
+    fn copy_to(&mut self) -> Result<(), CloseReason> {
+        match self.state {
+            State::Connecting | State::Handshake => {
+                // the handshake may still have records for the client
+                if let Some(ref mut tls) = self.client_tls {
+                    tls.flush(self.client_fd)?;
+                }
+                Ok(())
+            }
+            State::HalfClosedOut | State::Closing => Ok(()),
+            // the old backend is done with, only the swap is left
+            State::Rebinding if self.rebind.as_ref().is_some_and(|rebind| rebind.ready) => Ok(()),
+            state => {
+                let received = self.out_buf.received();
+                let (moved, empty) = (self.out_buf.moved, self.out_buf.is_empty());
+                let res = Context::copy(
+                    &mut self.out_buf,
+                    self.backend_fd,
+                    self.backend_tls.as_deref_mut(),
+                    self.client_fd,
+                    self.client_tls.as_deref_mut(),
+                    true,
+                );
+                if self.out_buf.received() != received {
+                    self.last_out = Instant::now();
+                }
+                if self.out_buf.moved != moved || empty {
+                    self.drained_out = Instant::now();
+                }
+                if self.out_buf.moved != moved && self.first_out.is_none() {
+                    self.first_out = Some(self.drained_out);
+                }
+                let eof = match res {
+                    Err(e) if e == libc::EPIPE && self.dest_gone(state, false)? => return Ok(()),
+                    res => res.map_err(|e| self.copy_error(e))?,
+                };
+                if !eof {
+                    return Ok(());
+                }
+                if state == State::Rebinding {
+                    if let Some(ref mut rebind) = self.rebind {
+                        rebind.ready = true;
+                    }
+                    return Ok(());
+                }
+                syscall!(libc::shutdown(self.client_fd, libc::SHUT_WR))?;
+                if state == State::HalfClosedIn {
+                    return Err(CloseReason::ClientEof);
+                }
+                self.state = State::HalfClosedOut;
+                Ok(())
+            }
+        }
+    }
+
+    // a reset on the backend side is told apart from one of the client, the
+    // backend socket is closed once the RST is in
+    fn copy_error(&self, e: i32) -> CloseReason {
+        if (e == libc::ECONNRESET || e == libc::EPIPE)
+            && net::peer_reset(self.backend_fd).unwrap_or(false)
+        {
+            CloseReason::BackendReset
+        } else {
+            CloseReason::Error(e)
+        }
+    }
+
+    // EPIPE towards the backend (in) or the client (out): that side shut its
+    // reading end, or closed after a half-close, and takes nothing more. The
+    // direction ends there, its source is no longer read and what is buffered
+    // for it is dropped, while the other direction carries on. False when
+    // EPIPE is not about that direction alone and stays an error
+    fn dest_gone(&mut self, state: State, in_: bool) -> Result<bool, CloseReason> {
+        let (closed, other) = if in_ {
+            (State::HalfClosedIn, State::HalfClosedOut)
+        } else {
+            (State::HalfClosedOut, State::HalfClosedIn)
+        };
+        if state != State::Streaming && state != other {
+            return Ok(false);
+        }
+        // a backend reset while both ways were open is not a half-close,
+        // one that closed after its EOF is
+        if in_ && state == State::Streaming && net::peer_reset(self.backend_fd).unwrap_or(false) {
+            return Ok(false);
+        }
+        let dropped = if in_ {
+            self.in_buf.discard()?
+        } else {
+            self.out_buf.discard()?
+        };
+        log!(
+            "conn {}: {} gone for {}, {} bytes dropped",
+            self.id,
+            if in_ { "backend" } else { "client" },
+            if in_ { "in" } else { "out" },
+            dropped
+        );
+        if state == other {
+            return Err(if in_ {
+                CloseReason::BackendEof
+            } else {
+                CloseReason::ClientEof
+            });
+        }
+        self.state = closed;
+        Ok(true)
+    }
+
+    // one pass over the directions that can make progress, client to backend
+    // first. A direction that ends the connection ends the pass as well
+    fn copy_pass(&mut self, from: bool, to: bool) -> Result<(), CloseReason> {
+        if from {
+            self.copy_from()?;
+        }
+        if to {
+            self.copy_to()?;
+        }
+        Ok(())
+    }
+
+    // the client is not read while rebinding, what it sent before goes to the
+    // old backend ahead of SHUT_WR
+    fn drain_for_rebind(&mut self) -> SysResult<()> {
+        if self.rebind.as_ref().is_some_and(|rebind| rebind.shut) {
+            return Ok(());
+        }
+        match self.backend_tls {
+            Some(ref mut tls) => tls.write_from(&mut self.in_buf, self.backend_fd)?,
+            None if !self.in_buf.is_empty() => self.in_buf.splice_out(self.backend_fd)?,
+            None => {}
+        }
+        if !self.in_buf.is_empty() {
+            return Ok(());
+        }
+        if let Some(ref mut tls) = self.backend_tls {
+            if !tls.shutdown(self.backend_fd)? {
+                return Ok(());
+            }
+        }
+        syscall!(libc::shutdown(self.backend_fd, libc::SHUT_WR))?;
+        if let Some(ref mut rebind) = self.rebind {
+            rebind.shut = true;
+        }
+        Ok(())
+    }
+
+    // a failing side can leave the other direction with data already in its
+    // pipe, it gets a last few nonblocking writes before the fds go. A
+    // destination that keeps taking a little at a time is given up on after
+    // FINAL_SPLICES calls rather than holding up the event loop
+    fn flush_final(&mut self) {
+        let out = match self.client_tls {
+            Some(ref mut tls) => tls
+                .write_from(&mut self.out_buf, self.client_fd)
+                .map(|_| false),
+            None => self
+                .out_buf
+                .splice_out_at_most(self.client_fd, FINAL_SPLICES),
+        };
+        let backend = self.has_backend();
+        let in_ = match self.backend_tls {
+            Some(ref mut tls) if backend => tls
+                .write_from(&mut self.in_buf, self.backend_fd)
+                .map(|_| false),
+            None if backend => self
+                .in_buf
+                .splice_out_at_most(self.backend_fd, FINAL_SPLICES),
+            _ => Ok(false),
+        };
+        if out == Ok(true) || in_ == Ok(true) {
+            log!(
+                "conn {}: client_fd {} closing after {} splices with {} bytes out and {} in left",
+                self.id,
+                self.client_fd,
+                FINAL_SPLICES,
+                self.out_buf.buffered,
+                self.in_buf.buffered
+            );
+        } else if !self.out_buf.is_empty() || !self.in_buf.is_empty() {
+            debug!(
+                "conn {}: client_fd {} closing with data left: {:?} {:?}",
+                self.id, self.client_fd, out, in_
+            );
+        }
+    }
+
+    // SHUT_WR towards the backend (in) or the client (out) as if the source
+    // of that direction had hit EOF, with what is buffered for it flushed
+    // first as far as it goes. The close reason once both directions are done
+    fn half_close(&mut self, dir: &str) -> Result<Option<CloseReason>, String> {
+        let (closed, other) = match dir {
+            "in" => (State::HalfClosedIn, State::HalfClosedOut),
+            "out" => (State::HalfClosedOut, State::HalfClosedIn),
+            _ => return Err(format!("bad direction {}, in or out", dir)),
+        };
+        if self.state != State::Streaming && self.state != other {
+            return Err(format!(
+                "client_fd {} is {}",
+                self.client_fd,
+                self.state.name()
+            ));
+        }
+        if self.client_tls.is_some() || self.backend_tls.is_some() {
+            return Err(format!("client_fd {} has a TLS side", self.client_fd));
+        }
+        let (buf, fd) = if dir == "in" {
+            (&mut self.in_buf, self.backend_fd)
+        } else {
+            (&mut self.out_buf, self.client_fd)
+        };
+        let _ = buf.splice_out(fd);
+        syscall!(libc::shutdown(fd, libc::SHUT_WR))
+            .map_err(|e| format!("shutdown failed: {}", e))?;
+        if self.state == other {
+            // the side shut here counts as the one that closed last
+            return Ok(Some(if dir == "in" {
+                CloseReason::BackendEof
+            } else {
+                CloseReason::ClientEof
+            }));
+        }
+        self.state = closed;
+        Ok(None)
+    }
+
+    // both fds leave the epoll set, what is buffered stays in the pipes
+    fn pause(&mut self) -> Result<(), String> {
+        match self.state {
+            State::Streaming | State::HalfClosedIn | State::HalfClosedOut => {}
+            state => return Err(format!("client_fd {} is {}", self.client_fd, state.name())),
+        }
+        if self.paused {
+            return Err(format!("client_fd {} is paused already", self.client_fd));
+        }
+        for &fd in &[self.client_fd, self.backend_fd] {
+            epoll_del(fd).map_err(|e| format!("epoll_del {} failed: {}", fd, e))?;
+        }
+        self.paused = true;
+        Ok(())
+    }
+
+    // adding the fds back raises the edges of what came in meanwhile
+    fn resume(&mut self) -> Result<(), String> {
+        if !self.paused {
+            return Err(format!("client_fd {} is not paused", self.client_fd));
+        }
+        for &(fd, pd) in &[(self.client_fd, self.in_pd), (self.backend_fd, self.out_pd)] {
+            epoll_register(fd, 3, pd, format_args!("conn {}: fd", self.id))
+                .map_err(|e| format!("epoll_add {} failed: {}", fd, e))?;
+        }
+        self.paused = false;
+        // the pause is not held against the idle and stall timeouts
+        self.last_in = Instant::now();
+        self.last_out = Instant::now();
+        self.drained_in = Instant::now();
+        self.drained_out = Instant::now();
+        Ok(())
+    }
+
+    // a copy hit -fairbytes and has to be queued for another turn
+    fn wants_turn(&self) -> bool {
+        !self.ready && (self.in_buf.yielded || self.out_buf.yielded)
+    }
+
+    fn rebind_ready(&self) -> bool {
+        self.rebind.as_ref().is_some_and(|rebind| rebind.ready)
+    }
+
+    // a finished direction is expected to stay quiet and only counts towards
+    // the idle timeout of the whole connection
+    fn idle_expired(&self, config: &Config, now: Instant) -> bool {
+        let idle_in = now - self.last_in;
+        let idle_out = now - self.last_out;
+        let expired =
+            |timeout: Option<Duration>, idle: Duration| timeout.is_some_and(|t| idle >= t);
+        expired(config.idle_timeout, idle_in.min(idle_out))
+            || (self.state != State::HalfClosedIn && expired(config.read_timeout, idle_in))
+            || (self.state != State::HalfClosedOut && expired(config.write_timeout, idle_out))
+    }
+
+    // the direction, in or out, whose destination took nothing of what is
+    // waiting for it for -stall. A slow reader behind a large socket buffer
+    // leaves the pipe alone for long, what it acks counts as progress too
+    fn stalled(&mut self, config: &Config, now: Instant) -> Option<&'static str> {
+        let timeout = config.stall_timeout?;
+        match self.state {
+            State::Streaming | State::HalfClosedIn | State::HalfClosedOut => {}
+            _ => return None,
+        }
+        if self.state != State::HalfClosedIn && !self.in_buf.is_empty() {
+            let acked = net::tcp_acked(self.backend_fd).unwrap_or(0);
+            if acked != self.acked.0 {
+                self.acked.0 = acked;
+                self.drained_in = now;
+            }
+            if now - self.drained_in >= timeout {
+                return Some("in");
+            }
+        }
+        if self.state != State::HalfClosedOut && !self.out_buf.is_empty() {
+            let acked = net::tcp_acked(self.client_fd).unwrap_or(0);
+            if acked != self.acked.1 {
+                self.acked.1 = acked;
+                self.drained_out = now;
+            }
+            if now - self.drained_out >= timeout {
+                return Some("out");
+            }
+        }
+        None
+    }
+
+    // the connect, or the tunnel through the upstream proxy, is taking too long
+    fn connect_expired(&self, backends: &[Backend], now: Instant) -> bool {
+        if self.backend_fd < 0
+            || !(self.state == State::Connecting || self.state == State::Handshake)
+        {
+            return false;
+        }
+        self.backend
+            .and_then(|i| backends[i].connect_timeout)
+            .is_some_and(|timeout| now - self.connect_start >= timeout)
+    }
+
+    // false while the SYN is still unanswered, a pending error shows in
+    // SO_ERROR and a finished connect has a peer
+    fn finish_connect(&mut self) -> SysResult<bool> {
+        match net::socket_error(self.backend_fd)? {
+            0 => {
+                if !net::is_connected(self.backend_fd)? {
+                    return Ok(false);
+                }
+                self.state = if self.handshake.is_some() {
+                    State::Handshake
+                } else {
+                    State::Streaming
+                };
+                Ok(true)
+            }
+            e => Err(e),
+        }
+    }
+
+    // true once the upstream proxy tunnel is up
+    fn advance_handshake(&mut self) -> SysResult<bool> {
+        let done = match self.handshake {
+            Some(ref mut handshake) => handshake.advance(self.backend_fd)?,
+            None => true,
+        };
+        if done {
+            self.handshake = None;
+            self.state = State::Streaming;
+        }
+        Ok(done)
+    }
+
+    fn shutdown(&mut self, reason: CloseReason) {
+        if self.close_reason.is_none() {
+            log!(
+                "conn {}: close client_fd {}: {}",
+                self.id,
+                self.client_fd,
+                reason
+            );
+            self.close_reason = Some(reason);
+        }
+        if self.in_pd != 0 {
+            // the fds may already be gone from the epoll set, teardown must go on regardless
+            for &fd in &[self.client_fd, self.backend_fd] {
+                if fd < 0 {
+                    continue;
+                }
+                match epoll_del(fd) {
+                    Ok(_) => {}
+                    Err(e) if e == libc::ENOENT || e == libc::EBADF => {
+                        debug!("epoll_del {} ignored: {}", fd, e)
+                    }
+                    Err(e) => log!("epoll_del {} failed: {}", fd, e),
+                }
+            }
+            mem::drop(unsafe { Box::from_raw(self.in_pd as *mut PollDesp) });
+            if self.out_pd != 0 {
+                mem::drop(unsafe { Box::from_raw(self.out_pd as *mut PollDesp) });
+            }
+            self.in_pd = 0;
+            self.out_pd = 0;
+        }
+        self.state = State::Closing;
+    }
+
+    fn sample_wire(&mut self) {
+        let sent = |fd: i32| {
+            if fd < 0 {
+                return (0, 0);
+            }
+            net::tcp_sent(fd).unwrap_or_default()
+        };
+        let (sent_in, retrans_in) = sent(self.backend_fd);
+        let (sent_out, retrans_out) = sent(self.client_fd);
+        self.wire = Some(stats::Wire {
+            sent_in,
+            retrans_in,
+            sent_out,
+            retrans_out,
+        });
+    }
+
+    // one JSON object per line
+    fn log_json(&self, backends: &[Backend]) {
+        let wire = match self.wire {
+            Some(w) => format!(
+                ",\"wire_in\":{},\"retrans_in\":{},\"wire_out\":{},\"retrans_out\":{}",
+                w.sent_in, w.retrans_in, w.sent_out, w.retrans_out
+            ),
+            None => String::new(),
+        };
+        log!(
+            "{{\"id\":{},\"client\":{},\"listener\":{},\"backend\":{},\"bytes_in\":{},\"bytes_out\":{}{}{},\"duration_ms\":{},\"close_reason\":\"{}\"}}",
+            self.id,
+            json_addr(self.client_addr),
+            json_addr(self.listener),
+            json_backend(self.backend.map(|i| &backends[i])),
+            self.in_buf.moved,
+            self.out_buf.moved,
+            wire,
+            self.json_ttfb(),
+            self.start.elapsed().as_millis(),
+            self.close_reason
+                .map_or("unknown".to_string(), |r| r.to_string())
+        );
+    }
+
+    // the time from accept to the first byte forwarded each way, null
+    // while there was none
+    fn json_ttfb(&self) -> String {
+        let ms = |first: Option<Instant>| {
+            first.map_or("null".to_string(), |t| {
+                (t - self.start).as_millis().to_string()
+            })
+        };
+        format!(
+            ",\"ttfb_in_ms\":{},\"ttfb_out_ms\":{}",
+            ms(self.first_in),
+            ms(self.first_out)
+        )
+    }
+
+    // a live connection as CONNS reports it
+    fn json_state(&self, backends: &[Backend]) -> String {
+        format!(
+            "{{\"id\":{},\"client_fd\":{},\"client\":{},\"listener\":{},\"backend\":{},\"state\":\"{}\",\"paused\":{},\"buffered_in\":{},\"buffered_out\":{},\"bytes_in\":{},\"bytes_out\":{}{},\"duration_ms\":{}}}",
+            self.id,
+            self.client_fd,
+            json_addr(self.client_addr),
+            json_addr(self.listener),
+            json_backend(self.backend.map(|i| &backends[i])),
+            self.state.name(),
+            self.paused,
+            self.in_buf.buffered,
+            self.out_buf.buffered,
+            self.in_buf.moved,
+            self.out_buf.moved,
+            self.json_ttfb(),
+            self.start.elapsed().as_millis()
+        )
+    }
+}
+
+fn json_addr(addr: Option<SocketAddr>) -> String {
+    match addr {
+        Some(addr) => format!("\"{}\"", addr),
+        None => "null".to_string(),
+    }
+}
+
+// a backend name comes from -d or its file and may hold anything
+fn json_string(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c < ' ' => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn json_backend(backend: Option<&Backend>) -> String {
+    match backend {
+        Some(&Backend {
+            addr: Some(addr), ..
+        }) => json_addr(Some(addr)),
+        Some(backend) => json_string(&backend.name),
+        None => "null".to_string(),
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        log!(
+            "conn {}: Context drop: {}+{} ({})",
+            self.id,
+            self.client_fd,
+            self.backend_fd,
+            self.copy_mode().name()
+        );
+        if let Some(skipped) = self.out_buf.tap.as_ref().map(|tap| tap.skipped) {
+            if skipped > 0 {
+                log!("conn {}: mirror skipped {} bytes", self.id, skipped);
+            }
+        }
+        unsafe {
+            libc::close(self.client_fd);
+            if self.backend_fd >= 0 {
+                libc::close(self.backend_fd);
+            }
+        }
+    }
+}
+
+struct PollDesp {
+    who: i32,
+    ctx: Rc<RefCell<Context>>,
+}
+
+// the fd an epoll event is for. Connections are registered with their
+// PollDesp pointer, user space addresses never have the top bit set, so the
+// other fds set it and carry their kind and index below
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token {
+    Listener(usize),
+    Signal,
+    Timer,
+    Control,
+    // a -sink client by its fd
+    Sink(i32),
+    Health,
+    // clients of the -health listener and the control socket by their fd
+    HealthClient(i32),
+    ControlClient(i32),
+    Conn(*mut PollDesp),
+}
+
+const TOKEN_SPECIAL: u64 = 1 << 63;
+
+// what a timer of Global::timers is for
+#[derive(Clone, Copy)]
+enum TimerEvent {
+    // the look for slow connects and idle connections, every interval
+    Tick(Duration),
+    // an -accept-delay client is due: fd, address, listener, tls
+    AcceptDelay(i32, Option<SocketAddr>, SocketAddr, bool),
+    // -runtime is up, handled by the event loop
+    Runtime,
+    // the drain after -runtime took its -drain-timeout
+    DrainTimeout,
+    // the -accept-cooldown after running out of fds is over
+    AcceptCooldown,
+    // a deadline of a -health or control client, by its token
+    Client(Token),
+}
+
+impl From<Token> for u64 {
+    fn from(token: Token) -> u64 {
+        match token {
+            Token::Listener(i) => TOKEN_SPECIAL | 1 << 32 | i as u64,
+            Token::Signal => TOKEN_SPECIAL | 2 << 32,
+            Token::Timer => TOKEN_SPECIAL | 3 << 32,
+            Token::Control => TOKEN_SPECIAL | 4 << 32,
+            Token::Sink(fd) => TOKEN_SPECIAL | 5 << 32 | fd as u32 as u64,
+            Token::Health => TOKEN_SPECIAL | 6 << 32,
+            Token::HealthClient(fd) => TOKEN_SPECIAL | 7 << 32 | fd as u32 as u64,
+            Token::ControlClient(fd) => TOKEN_SPECIAL | 8 << 32 | fd as u32 as u64,
+            Token::Conn(pd) => pd as u64,
+        }
+    }
+}
+
+impl From<u64> for Token {
+    fn from(data: u64) -> Token {
+        if data & TOKEN_SPECIAL == 0 {
+            return Token::Conn(data as *mut PollDesp);
+        }
+        match (data & !TOKEN_SPECIAL) >> 32 {
+            1 => Token::Listener(data as u32 as usize),
+            2 => Token::Signal,
+            3 => Token::Timer,
+            4 => Token::Control,
+            5 => Token::Sink(data as u32 as i32),
+            6 => Token::Health,
+            7 => Token::HealthClient(data as u32 as i32),
+            8 => Token::ControlClient(data as u32 as i32),
+            _ => panic!("bad epoll token {:#x}", data),
+        }
+    }
+}
+
+// epoll_add that survives finding fd in the set already: that entry is
+// left from an fd that was not taken out on its way, it is logged and made
+// to point at the new owner, what describes it
+fn epoll_register(fd: i32, rw: i32, data: u64, what: fmt::Arguments) -> SysResult<()> {
+    match epoll_add(fd, rw, data) {
+        Err(libc::EEXIST) => {
+            log!(
+                "{} {} was in the epoll set already, stale entry replaced",
+                what,
+                fd
+            );
+            epoll_mod(fd, rw, data).map(|_| ())
+        }
+        r => r.map(|_| ()),
+    }
+}
+
+// the listeners and other fds of the process are set up once, not having
+// them watched is fatal
+fn register_or_exit(fd: i32, token: Token, what: &str) {
+    if let Err(e) = epoll_register(fd, 1, token.into(), format_args!("{}", what)) {
+        eprintln!("epoll_add {} {} failed: {}", what, fd, e);
+        process::exit(1);
+    }
+}
+
+// epoll_del for the fds the process keeps, one that is gone already is
+// only logged
+fn unregister(fd: i32, what: &str) {
+    if let Err(e) = epoll_del(fd) {
+        log!("epoll_del {} {} failed: {}", what, fd, e);
+    }
+}
+
+impl Drop for PollDesp {
+    fn drop(&mut self) {
+        debug!("PollDesp drop: {}", self.who);
+    }
+}
+
+struct Backend {
+    name: String,
+    host: String,
+    port: u16,
+    // None when the backend is reached through an upstream proxy
+    addr: Option<SocketAddr>,
+    weight: i64,
+    // smooth weighted round robin state
+    current: i64,
+    server_name: Option<ServerName<'static>>,
+    // the ;ct= of the backend, else -ct
+    connect_timeout: Option<Duration>,
+}
+
+// a listen socket by its index in Token::Listener
+struct Listener {
+    fd: i32,
+    tls: bool,
+    family: Family,
+    addr: SocketAddr,
+    // taken out of the epoll set by LISTEN disable
+    disabled: bool,
+}
+
+struct Global {
+    config: Config,
+    listeners: Vec<Listener>,
+    // the listeners are out of the epoll set for an upgrade or -runtime
+    draining: bool,
+    // the listeners are out of the epoll set for the -accept-cooldown
+    cooling: bool,
+    // the -d backends come first, then those of the -host routes
+    backends: Vec<Backend>,
+    // how many -d backends new connections are spread over
+    pool: usize,
+    // host pattern to index into backends
+    routes: Vec<(String, usize)>,
+    // -sticky, the backend each client IP was last sent to and when
+    sticky: HashMap<IpAddr, (usize, Instant)>,
+    tls_config: Option<Arc<rustls::ServerConfig>>,
+    backend_tls: Option<Arc<rustls::ClientConfig>>,
+    src_ip: Option<IpAddr>,
+    src_ports: Option<net::PortRange>,
+    // -mptcp and the kernel has it
+    mptcp: bool,
+    upstream: Option<(SocketAddr, upstream::Proto)>,
+    mirror: Option<SocketAddr>,
+    connecting: usize,
+    connect_latency: stats::Histogram,
+    // accept to the first byte to the client, of the closed connections
+    first_byte_out: stats::Histogram,
+    close_reasons: BTreeMap<&'static str, u64>,
+    // clients closed right after accept, by the kind of reject
+    rejects: BTreeMap<&'static str, u64>,
+    traffic: stats::Traffic,
+    // parallel to backends
+    backend_stats: Vec<stats::BackendStats>,
+    full_in: stats::PipeFull,
+    full_out: stats::PipeFull,
+    pending: VecDeque<Rc<RefCell<Context>>>,
+    // the id of the last connection
+    last_id: u64,
+    timers: timer::Timers<TimerEvent>,
+    // -accept-delay clients waiting for their timer
+    delayed: usize,
+    // accepts of all listeners, kept with -shed or -shed-rate
+    accept_rate: stats::AcceptRate,
+    // the shed fractions of the accepts so far, a whole one drops a client
+    shed_credit: f64,
+    // every live connection by client fd
+    conns: BTreeMap<i32, Weak<RefCell<Context>>>,
+    // source address of each backend socket to its client fd, a client
+    // coming from one of them is the proxy connected to itself
+    backend_socks: HashMap<SocketAddr, i32>,
+    // -health and control clients by fd
+    clients: HashMap<i32, control::Client>,
+}
+
+impl Global {
+    fn can_connect(&self) -> bool {
+        self.config.connlimit == 0 || self.connecting < self.config.connlimit
+    }
+
+    // every backend gains its weight, the one ahead is picked and pays the
+    // total back, which spreads the picks of a heavy backend over the cycle
+    fn pick_backend(&mut self) -> usize {
+        let mut best = 0;
+        let mut total = 0;
+        for i in 0..self.pool {
+            let backend = &mut self.backends[i];
+            backend.current += backend.weight;
+            total += backend.weight;
+            if backend.current > self.backends[best].current {
+                best = i;
+            }
+        }
+        self.backends[best].current -= total;
+        best
+    }
+}
+
+// teardown is deferred to the end of the event batch, later events of the
+// batch may still carry the PollDesp pointers of the context
+fn defer_shutdown(
+    g: &mut Global,
+    defer_free: &mut DeferFree,
+    ctx: &Rc<RefCell<Context>>,
+    reason: CloseReason,
+) {
+    let mut c = ctx.borrow_mut();
+    if c.state != State::Closing {
+        if c.connect_in_flight() {
+            g.connecting -= 1;
+        }
+        c.flush_final();
+        c.state = State::Closing;
+        defer_free.push((ctx.clone(), reason));
+    }
+}
+
+fn close_context(g: &mut Global, ctx: &mut Context, reason: CloseReason) {
+    g.conns.remove(&ctx.client_fd);
+    if let (CloseReason::ConnectFailed(_), Some(i)) = (reason, ctx.backend) {
+        g.backend_stats[i].connect_failures += 1;
+        g.backend_stats[i].down = true;
+        // the next connect of the client IP goes to the balancer
+        if let Some(addr) = ctx.client_addr {
+            let ip = addr.ip();
+            if g.sticky.get(&ip).is_some_and(|&(j, _)| j == i) {
+                g.sticky.remove(&ip);
+            }
+        }
+    }
+    // a client that got nothing from a backend yet can be told why it goes
+    if ctx.out_buf.moved == 0 {
+        let why = match reason {
+            CloseReason::ConnectFailed(_) => Some(Reject::ConnectFailed),
+            CloseReason::HeaderTimeout | CloseReason::Error(_) if ctx.routing => {
+                Some(Reject::Route)
+            }
+            _ => None,
+        };
+        if let Some(why) = why {
+            reject(g, ctx.client_fd, ctx.client_tls.is_some(), why);
+        }
+    }
+    release_backend(g, ctx);
+    ctx.shutdown(reason);
+    if g.config.tcp_info {
+        ctx.sample_wire();
+    }
+    g.traffic.add(ctx.in_buf.moved, ctx.out_buf.moved, ctx.wire);
+    if let Some(first) = ctx.first_out {
+        g.first_byte_out.record(first - ctx.start);
+    }
+    g.full_in.add(ctx.in_buf.full);
+    g.full_out.add(ctx.out_buf.full);
+    *g.close_reasons.entry(reason.name()).or_insert(0) += 1;
+    if g.config.log_json {
+        ctx.log_json(&g.backends);
+    }
+}
+
+// counts what the connection moved for the backend whose socket it lets go of
+fn release_backend(g: &mut Global, ctx: &mut Context) {
+    let i = match ctx.backend {
+        Some(i) if ctx.backend_fd >= 0 => i,
+        _ => return,
+    };
+    if let Ok(addr) = net::local_addr(ctx.backend_fd) {
+        g.backend_socks.remove(&addr);
+    }
+    let stats = &mut g.backend_stats[i];
+    stats.active -= 1;
+    stats.bytes_in += ctx.in_buf.moved - ctx.credited.0;
+    stats.bytes_out += ctx.out_buf.moved - ctx.credited.1;
+    ctx.credited = (ctx.in_buf.moved, ctx.out_buf.moved);
+}
+
+// what -health reports: up while any backend takes connections, as far as
+// the last connect to each tells, and down while draining
+fn healthy(g: &Global) -> bool {
+    !g.draining && (g.config.sink || g.backend_stats.iter().any(|stats| !stats.down))
+}
+
+// the counters with the bytes of the live connections added
+fn backend_stats(g: &Global) -> Vec<stats::BackendStats> {
+    let mut all = g.backend_stats.clone();
+    for ctx in g.conns.values().filter_map(Weak::upgrade) {
+        let ctx = ctx.borrow();
+        if let (Some(i), true) = (ctx.backend, ctx.backend_fd >= 0) {
+            all[i].bytes_in += ctx.in_buf.moved - ctx.credited.0;
+            all[i].bytes_out += ctx.out_buf.moved - ctx.credited.1;
+        }
+    }
+    all
+}
+
+fn connect_backend(g: &mut Global, ctx: &Rc<RefCell<Context>>) -> SysResult<()> {
+    // a routed client comes with its backend
+    let (routed, client_addr, dest) = {
+        let c = ctx.borrow();
+        (c.backend, c.client_addr, c.dest)
+    };
+    let i = match (routed, dest) {
+        (Some(i), _) => i,
+        // a -redirect client goes where it was headed, past the -d backends
+        (None, Some(_)) if g.config.redirect => g.pool,
+        _ => sticky_backend(g, client_addr),
+    };
+    connect_to(g, ctx, i)
+}
+
+// with -sticky a client IP goes back to the backend it was last sent to
+// within the ttl, the balancer picks for the others
+fn sticky_backend(g: &mut Global, client_addr: Option<SocketAddr>) -> usize {
+    let (ttl, ip) = match (g.config.sticky, client_addr) {
+        (Some(ttl), Some(addr)) => (ttl, addr.ip()),
+        _ => return g.pick_backend(),
+    };
+    let now = Instant::now();
+    let i = match g.sticky.get(&ip) {
+        Some(&(i, at)) if now - at < ttl => i,
+        _ => g.pick_backend(),
+    };
+    g.sticky.insert(ip, (i, now));
+    i
+}
+
+fn connect_to(g: &mut Global, ctx: &Rc<RefCell<Context>>, i: usize) -> SysResult<()> {
+    // a failure from here on is one of this backend
+    ctx.borrow_mut().backend = Some(i);
+    // the ClientHello is queued right away and goes out on the first OUT edge
+    let backend = &g.backends[i];
+    let backend_tls = match (&g.backend_tls, &backend.server_name) {
+        (Some(config), Some(name)) => match tls::TlsStream::client(config, name) {
+            Ok(stream) => Some(Box::new(stream)),
+            Err(e) => {
+                log!("tls setup for backend failed: {}", e);
+                return Err(libc::EPROTO);
+            }
+        },
+        _ => None,
+    };
+    let (dest, client_addr) = {
+        let c = ctx.borrow();
+        (c.dest, c.client_addr)
+    };
+    let (connect_addr, handshake) = match g.upstream {
+        Some((addr, ref proto)) => (
+            addr,
+            Some(Box::new(upstream::Handshake::new(
+                proto,
+                &backend.host,
+                backend.port,
+            ))),
+        ),
+        // a client without a destination of its own cannot be rebound to
+        // the original destination backend
+        None => (backend.addr.or(dest).ok_or(libc::EDESTADDRREQ)?, None),
+    };
+    let md5 = Some(&g.config.md5_key[..]).filter(|key| !key.is_empty());
+    let backend_fd = match (g.config.transparent, client_addr) {
+        // the backend sees the client's address, its replies are routed back
+        // here by the TPROXY setup
+        (true, Some(client)) => {
+            net::connect_transparent(&connect_addr, client.ip(), &g.config.backend_sock_opts, md5)?
+        }
+        _ => net::connect_tcp(
+            &connect_addr,
+            g.src_ip,
+            g.src_ports.as_mut(),
+            g.mptcp,
+            &g.config.backend_sock_opts,
+            md5,
+        )?,
+    };
+    let out_pd = Box::into_raw(Box::new(PollDesp {
+        who: 1,
+        ctx: ctx.clone(),
+    })) as u64;
+    let id = ctx.borrow().id;
+    if let Err(e) = epoll_register(
+        backend_fd,
+        3,
+        out_pd,
+        format_args!("conn {}: backend_fd", id),
+    ) {
+        mem::drop(unsafe { Box::from_raw(out_pd as *mut PollDesp) });
+        unsafe { libc::close(backend_fd) };
+        return Err(e);
+    }
+    let mut ctx = ctx.borrow_mut();
+    log!(
+        "conn {}: associate client_fd {} backend_fd {}",
+        ctx.id,
+        ctx.client_fd,
+        backend_fd
+    );
+    if let Ok(addr) = net::local_addr(backend_fd) {
+        g.backend_socks.insert(addr, ctx.client_fd);
+    }
+    g.connecting += 1;
+    g.backend_stats[i].connects += 1;
+    g.backend_stats[i].active += 1;
+    ctx.connect_start = Instant::now();
+    ctx.backend_fd = backend_fd;
+    ctx.handshake = handshake;
+    ctx.backend_tls = backend_tls;
+    ctx.out_pd = out_pd;
+    Ok(())
+}
+
+fn request_connect(g: &mut Global, ctx: &Rc<RefCell<Context>>) -> SysResult<()> {
+    if !g.can_connect() {
+        let mut c = ctx.borrow_mut();
+        log!("conn {}: queue client_fd {}", c.id, c.client_fd);
+        c.queued = true;
+        g.pending.push_back(ctx.clone());
+        return Ok(());
+    }
+    connect_backend(g, ctx)
+}
+
+// a backend that resolves to the proxy itself, through DNS, a NAT or a
+// listener on all addresses, would have every connection open another one
+// until the fds run out. The connection that came around is handed back
+fn backend_loop(g: &Global, client_addr: Option<SocketAddr>) -> Option<Rc<RefCell<Context>>> {
+    let client_fd = *g.backend_socks.get(&client_addr?)?;
+    log!(
+        "client {} is the backend of client_fd {}, the backend loops back to the proxy",
+        client_addr?,
+        client_fd
+    );
+    g.conns.get(&client_fd).and_then(Weak::upgrade)
+}
+
+// with -accept-age, a client that waited longer in the backlog has likely
+// given up already and is dropped in favor of fresher ones
+fn stale_accept(g: &Global, fd: i32) -> bool {
+    let max = match g.config.accept_age {
+        Some(max) => max,
+        None => return false,
+    };
+    match net::peer_quiet(fd) {
+        Ok(quiet) if quiet > max => {
+            log!("drop client_fd {}: {:?} in the accept queue", fd, quiet);
+            true
+        }
+        _ => false,
+    }
+}
+
+// -reject-response to a client about to be closed, what does not fit the
+// socket buffer at once is left out. What the client sent is dropped, a
+// close with unread data resets the connection and the client may lose the
+// response to it. A TLS client gets nothing, the bytes would only break its
+// handshake
+fn reject(g: &Global, fd: i32, tls: bool, why: Reject) {
+    let config = &g.config;
+    if config.reject_response.is_empty()
+        || tls
+        || !(config.reject_on.is_empty() || config.reject_on.contains(&why))
+    {
+        return;
+    }
+    let _ = net::discard(fd, 1 << 20);
+    match net::send_some(fd, &config.reject_response) {
+        Ok(n) => debug!("reject client_fd {}: {}, {} bytes sent", fd, why.name(), n),
+        Err(e) => debug!(
+            "reject client_fd {}: {}, send failed: {}",
+            fd,
+            why.name(),
+            e
+        ),
+    }
+}
+
+// a client closed right after accept never was a connection, it is counted
+// by the kind of reject and not by close reason
+fn turn_away(g: &mut Global, fd: i32, tls: bool, why: Reject) {
+    *g.rejects.entry(why.name()).or_insert(0) += 1;
+    reject(g, fd, tls, why);
+    unsafe { libc::close(fd) };
+}
+
+// -shed and -shed-rate, the fraction of new clients to drop: none up to
+// the thresholds, then rising to all at twice the -shed connections, or
+// what the accepts exceed -shed-rate by, which lets that many a second in
+fn shed_fraction(g: &Global, rate: u64) -> f64 {
+    let mut fraction: f64 = 0.0;
+    let (conns, limit) = (g.conns.len(), g.config.shed_conns);
+    if limit != 0 && conns > limit {
+        fraction = (conns - limit) as f64 / limit as f64;
+    }
+    let limit = g.config.shed_rate;
+    if limit != 0 && rate > limit {
+        fraction = fraction.max((rate - limit) as f64 / rate as f64);
+    }
+    fraction.min(1.0)
+}
+
+// whether to drop a client just accepted, before anything is spent on it.
+// The fractions of the accepts add up and each whole one drops a client,
+// which spreads the drops evenly like the weights of the balancer
+fn shed(g: &mut Global, fd: i32) -> bool {
+    if g.config.shed_conns == 0 && g.config.shed_rate == 0 {
+        return false;
+    }
+    let now = Instant::now();
+    g.accept_rate.add(now);
+    let rate = g.accept_rate.rate(now);
+    let fraction = shed_fraction(g, rate);
+    if fraction == 0.0 {
+        g.shed_credit = 0.0;
+        return false;
+    }
+    g.shed_credit += fraction;
+    if g.shed_credit < 1.0 {
+        return false;
+    }
+    g.shed_credit -= 1.0;
+    debug!(
+        "shed client_fd {}: {} connections, {} accepts/s",
+        fd,
+        g.conns.len(),
+        rate
+    );
+    true
+}
+
+fn family_allowed(fd: i32, addr: Option<SocketAddr>, family: Family) -> bool {
+    match addr {
+        Some(addr) if family.allows(&addr) => true,
+        Some(addr) => {
+            debug!(
+                "reject client_fd {} from {}: not {}",
+                fd,
+                addr,
+                family.name()
+            );
+            false
+        }
+        None => false,
+    }
+}
+
+// -sink keeps nothing of a client but its fd
+fn sink_client(g: &mut Global, fd: i32) {
+    if let Err(e) = epoll_register(fd, 1, Token::Sink(fd).into(), format_args!("client_fd")) {
+        log!("epoll_add client_fd {} failed: {}", fd, e);
+        unsafe { libc::close(fd) };
+        return;
+    }
+    // what came with the handshake has no edge of its own
+    drain_sink(g, fd);
+}
+
+fn drain_sink(g: &mut Global, fd: i32) {
+    let reason = loop {
+        match net::discard(fd, 1 << 20) {
+            Ok(0) => break CloseReason::ClientEof,
+            Ok(n) => g.traffic.add(n as u64, 0, None),
+            Err(e) if e == libc::EAGAIN => return,
+            Err(e) => break CloseReason::Error(e),
+        }
+    };
+    log!("close client_fd {}: {}", fd, reason);
+    *g.close_reasons.entry(reason.name()).or_insert(0) += 1;
+    // closing drops the fd from the epoll set
+    unsafe { libc::close(fd) };
+}
+
+// a -health or control client joins the epoll set like a connection, so
+// one that sends its request slowly does not hold up the others. Every
+// deadline it gets has a timer of its own
+fn watch_client(g: &mut Global, fd: i32, token: Token, timeout: Duration) {
+    if let Err(e) = epoll_register(fd, 3, token.into(), format_args!("client_fd")) {
+        log!("epoll_add client_fd {} failed: {}", fd, e);
+        unsafe { libc::close(fd) };
+        return;
+    }
+    g.clients
+        .insert(fd, control::Client::new(Instant::now() + timeout));
+    arm_client(g, fd, token);
+}
+
+fn arm_client(g: &mut Global, fd: i32, token: Token) {
+    let deadline = g.clients[&fd].deadline;
+    if let Err(e) = g.timers.add(deadline, TimerEvent::Client(token)) {
+        log!("client_fd {} timer failed: {}", fd, e);
+        close_client(g, fd);
+    }
+}
+
+fn close_client(g: &mut Global, fd: i32) {
+    // closing drops the fd from the epoll set
+    if g.clients.remove(&fd).is_some() {
+        unsafe { libc::close(fd) };
+    }
+}
+
+// what a client sent once its request is complete, up to max bytes of it.
+// None while more has to come, or after it was answered already
+fn client_request(
+    g: &mut Global,
+    fd: i32,
+    max: usize,
+    complete: fn(&[u8]) -> bool,
+) -> Option<Vec<u8>> {
+    let client = g.clients.get_mut(&fd)?;
+    if client.replying() {
+        return None;
+    }
+    if let Err(e) = client.read(fd, max) {
+        debug!("client_fd {} failed: {}", fd, e);
+        close_client(g, fd);
+        return None;
+    }
+    if !client.eof() && !complete(client.input()) {
+        return None;
+    }
+    Some(client.input().to_vec())
+}
+
+// the reply goes out as far as the socket takes it, the rest on the next
+// events until it is all out and the client is closed
+fn reply_client(g: &mut Global, fd: i32, token: Token, output: Vec<u8>) {
+    if let Some(client) = g.clients.get_mut(&fd) {
+        client.reply(output);
+        arm_client(g, fd, token);
+    }
+    flush_client(g, fd);
+}
+
+fn flush_client(g: &mut Global, fd: i32) {
+    let client = match g.clients.get_mut(&fd) {
+        Some(client) if client.replying() => client,
+        _ => return,
+    };
+    match client.flush(fd) {
+        Ok(false) => {}
+        Ok(true) => close_client(g, fd),
+        Err(e) => {
+            debug!("client_fd {} reply failed: {}", fd, e);
+            close_client(g, fd);
+        }
+    }
+}
+
+// a request that is not complete by its deadline is answered as it is, a
+// reply not taken by then is given up. The timer of an earlier deadline or
+// of a client gone finds none of its own
+fn client_timeout(g: &mut Global, token: Token, deadline: Instant) {
+    let (fd, output) = match token {
+        Token::HealthClient(fd) => (fd, http::health_response(healthy(g))),
+        Token::ControlClient(fd) => (fd, b"ERR timed out\n".to_vec()),
+        _ => unreachable!(),
+    };
+    match g.clients.get(&fd) {
+        Some(client) if client.deadline == deadline => {
+            if client.replying() {
+                debug!("client_fd {} did not take its reply", fd);
+                close_client(g, fd);
+            } else {
+                reply_client(g, fd, token, output);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn serve_client(
+    g: &mut Global,
+    fd: i32,
+    addr: Option<SocketAddr>,
+    listener: SocketAddr,
+    tls: bool,
+) {
+    net::set_sock_opts(fd, &g.config.client_sock_opts);
+    if g.config.sink {
+        sink_client(g, fd);
+    } else {
+        handle_client(g, fd, addr, listener, tls);
+    }
+}
+
+// -accept-delay holds a client back before anything is done with it, to
+// test how clients cope with a slow proxy. Its fd is not in the epoll set
+// until its timer fires
+fn delay_client(
+    g: &mut Global,
+    delay: Duration,
+    fd: i32,
+    addr: Option<SocketAddr>,
+    listener: SocketAddr,
+    tls: bool,
+) {
+    let event = TimerEvent::AcceptDelay(fd, addr, listener, tls);
+    if let Err(e) = g.timers.add(Instant::now() + delay, event) {
+        log!("client_fd {} timer failed: {}, serving it now", fd, e);
+        serve_client(g, fd, addr, listener, tls);
+        return;
+    }
+    g.delayed += 1;
+}
+
+fn timer_fired(
+    g: &mut Global,
+    defer_free: &mut DeferFree,
+    deadline: Instant,
+    event: TimerEvent,
+    now: Instant,
+) {
+    match event {
+        TimerEvent::Tick(interval) => {
+            expire_timeouts(g, defer_free);
+            let next = timer::next_period(deadline, interval, now);
+            if let Err(e) = g.timers.add(next, event) {
+                log!("timer failed: {}, timeouts are not checked any more", e);
+            }
+        }
+        TimerEvent::AcceptDelay(fd, addr, listener, tls) => {
+            g.delayed -= 1;
+            serve_client(g, fd, addr, listener, tls);
+        }
+        TimerEvent::AcceptCooldown => {
+            g.cooling = false;
+            if !g.draining {
+                log!("accepting again");
+                watch_listeners(g);
+            }
+        }
+        TimerEvent::DrainTimeout => {
+            log!(
+                "runtime: drain timeout, closing {} connections",
+                g.conns.len()
+            );
+            let live: Vec<_> = g.conns.values().filter_map(Weak::upgrade).collect();
+            for ctx in live {
+                defer_shutdown(g, defer_free, &ctx, CloseReason::DrainTimeout);
+            }
+            // -accept-delay clients were never served, they go as they are
+            let delayed = g
+                .timers
+                .cancel(|event| matches!(event, TimerEvent::AcceptDelay(..)));
+            for event in delayed {
+                if let TimerEvent::AcceptDelay(fd, ..) = event {
+                    g.delayed -= 1;
+                    unsafe { libc::close(fd) };
+                }
+            }
+        }
+        TimerEvent::Client(token) => client_timeout(g, token, deadline),
+        TimerEvent::Runtime => unreachable!(),
+    }
+}
+
+fn handle_client(
+    g: &mut Global,
+    client_fd: i32,
+    client_addr: Option<SocketAddr>,
+    listener: SocketAddr,
+    tls: bool,
+) {
+    // a -transparent listener takes connections for addresses of other
+    // hosts, the one the client asked for is the backend
+    let dest = match (g.config.transparent, client_addr) {
+        // a client of -redirect that was not redirected goes to -d
+        (false, _) if g.config.redirect => match net::original_dst(client_fd) {
+            Ok(dest) => Some(dest),
+            Err(e) => {
+                debug!("original destination of client_fd {}: {}", client_fd, e);
+                None
+            }
+        },
+        (false, _) => None,
+        (true, Some(_)) => match net::local_addr(client_fd) {
+            Ok(dest) => Some(dest),
+            Err(e) => {
+                log!(
+                    "original destination of client_fd {} failed: {}",
+                    client_fd,
+                    e
+                );
+                unsafe { libc::close(client_fd) };
+                return;
+            }
+        },
+        (true, None) => {
+            log!("client_fd {} has no address to connect from", client_fd);
+            unsafe { libc::close(client_fd) };
+            return;
+        }
+    };
+    let client_tls = if tls {
+        let config = g.tls_config.as_ref().unwrap();
+        match tls::TlsStream::server(config) {
+            Ok(stream) => Some(Box::new(stream)),
+            Err(e) => {
+                log!("tls setup for client_fd {} failed: {}", client_fd, e);
+                unsafe { libc::close(client_fd) };
+                return;
+            }
+        }
+    } else {
+        None
+    };
+    // short of fds the client is turned away, the proxy carries on
+    let ctx = match Context::new(
+        client_fd,
+        client_addr,
+        client_tls,
+        g.config.in_buf_size,
+        g.config.out_buf_size,
+        g.config.chunk_size,
+        g.config.fair_bytes,
+    ) {
+        Ok(mut ctx) => {
+            g.last_id += 1;
+            ctx.id = g.last_id;
+            log!("conn {}: client_fd {}", ctx.id, client_fd);
+            Rc::new(RefCell::new(ctx))
+        }
+        Err(e) => {
+            log!("pipe for client_fd {} failed: {}", client_fd, e);
+            unsafe { libc::close(client_fd) };
+            return;
+        }
+    };
+    let in_pd = Box::into_raw(Box::new(PollDesp {
+        who: 0,
+        ctx: ctx.clone(),
+    })) as u64;
+    let id = ctx.borrow().id;
+    if let Err(e) = epoll_register(client_fd, 3, in_pd, format_args!("conn {}: client_fd", id)) {
+        log!(
+            "conn {}: epoll_add client_fd {} failed: {}",
+            id,
+            client_fd,
+            e
+        );
+        // dropping the last Rc closes the client
+        mem::drop(unsafe { Box::from_raw(in_pd as *mut PollDesp) });
+        return;
+    }
+    // what goes out to a TLS client is not spliced
+    if let (Some(addr), false) = (g.mirror, tls) {
+        match Tap::connect(&addr) {
+            Ok(tap) => ctx.borrow_mut().out_buf.tap = Some(tap),
+            Err(e) => log!(
+                "conn {}: mirror for client_fd {} failed: {}",
+                ctx.borrow().id,
+                client_fd,
+                e
+            ),
+        }
+    }
+    // the TLS handshake hides the request from a peek
+    let routing = (g.config.http_route || g.config.route_prefix) && !tls;
+    ctx.borrow_mut().in_pd = in_pd;
+    ctx.borrow_mut().listener = Some(listener);
+    ctx.borrow_mut().dest = dest;
+    ctx.borrow_mut().routing = routing;
+    g.conns.insert(client_fd, Rc::downgrade(&ctx));
+    // in lazy mode the backend is connected on the first client data, when
+    // routing once the request head says where to
+    if g.config.lazy || routing {
+        return;
+    }
+    if let Err(e) = request_connect(g, &ctx) {
+        log!("conn {}: connect backend failed: {}", ctx.borrow().id, e);
+        close_context(g, &mut ctx.borrow_mut(), CloseReason::ConnectFailed(e));
+    }
+}
+
+// picks the backend by the Host header of the first request, which is only
+// peeked at and goes to the backend as it is. Ok(false) while the head is
+// still coming in
+fn route_client(g: &Global, ctx: &Rc<RefCell<Context>>) -> Result<bool, CloseReason> {
+    let mut c = ctx.borrow_mut();
+    if g.config.route_prefix {
+        return match route_prefix(g, &c)? {
+            Some(i) => {
+                c.routing = false;
+                c.backend = Some(i);
+                Ok(true)
+            }
+            None => Ok(false),
+        };
+    }
+    let max = g.config.header_max();
+    let mut head = vec![0u8; max];
+    let n = match net::peek_into(c.client_fd, &mut head) {
+        Ok(0) => return Err(CloseReason::ClientEof),
+        Ok(n) => n,
+        Err(e) if e == libc::EAGAIN => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    let route = match http::request_host(&head[..n], max) {
+        // nothing more is coming to complete the head, it goes out as it is
+        http::Head::Incomplete if net::peer_closed(c.client_fd)? => None,
+        http::Head::Incomplete => return Ok(false),
+        http::Head::TooLarge => {
+            log!(
+                "conn {}: client_fd {} request head over {} bytes",
+                c.id,
+                c.client_fd,
+                max
+            );
+            return Err(CloseReason::Error(libc::EMSGSIZE));
+        }
+        http::Head::Host(Some(host)) => http::match_host(&g.routes, &host).cloned(),
+        _ => None,
+    };
+    // unmatched hosts and anything else go to the -d backends
+    if let Some(i) = route {
+        debug!(
+            "conn {}: client_fd {} routed to {}",
+            c.id, c.client_fd, g.backends[i].name
+        );
+    }
+    c.routing = false;
+    c.backend = route;
+    Ok(true)
+}
+
+// longest ROUTE: line a -route-prefix client may start with, unless
+// -header-max
+const MAX_PREFIX: usize = 256;
+
+// the backend of the ROUTE: name line a -route-prefix client starts with,
+// the line is taken off the socket so the backend never sees it. Ok(None)
+// while the line is still coming in. A client without one, or with a name
+// no -host has, is dropped
+fn route_prefix(g: &Global, c: &Context) -> Result<Option<usize>, CloseReason> {
+    let max = g.config.header_max();
+    let mut line = vec![0u8; max];
+    let n = match net::peek_into(c.client_fd, &mut line) {
+        Ok(0) => return Err(CloseReason::ClientEof),
+        Ok(n) => n,
+        Err(e) if e == libc::EAGAIN => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let end = match line[..n].iter().position(|&b| b == b'\n') {
+        Some(end) => end,
+        None if n < max && !net::peer_closed(c.client_fd)? => return Ok(None),
+        None => {
+            log!(
+                "conn {}: client_fd {} sent no route line",
+                c.id,
+                c.client_fd
+            );
+            return Err(CloseReason::Error(libc::EPROTO));
+        }
+    };
+    let line = String::from_utf8_lossy(&line[..end]);
+    let line = line.trim_end_matches('\r');
+    let route = line
+        .strip_prefix("ROUTE:")
+        .map(|name| name.trim().to_ascii_lowercase())
+        .and_then(|name| http::match_host(&g.routes, &name).cloned());
+    let i = match route {
+        Some(i) => i,
+        None => {
+            log!(
+                "conn {}: client_fd {} bad route line {:?}",
+                c.id,
+                c.client_fd,
+                line
+            );
+            return Err(CloseReason::Error(libc::EPROTO));
+        }
+    };
+    net::discard(c.client_fd, end + 1)?;
+    debug!(
+        "conn {}: client_fd {} routed to {}",
+        c.id, c.client_fd, g.backends[i].name
+    );
+    Ok(Some(i))
+}
+
+type DeferFree = Vec<(Rc<RefCell<Context>>, CloseReason)>;
+type ReadyQueue = VecDeque<Rc<RefCell<Context>>>;
+
+// the live connection on the client_fd a control command names
+fn control_conn(g: &Global, fd: &str) -> Result<Rc<RefCell<Context>>, String> {
+    let fd = fd
+        .parse::<i32>()
+        .map_err(|_| format!("bad client_fd {}", fd))?;
+    g.conns
+        .get(&fd)
+        .and_then(Weak::upgrade)
+        .ok_or_else(|| format!("no connection on client_fd {}", fd))
+}
+
+// one command line per control connection, answered with OK or ERR
+fn control_command(
+    g: &mut Global,
+    line: &str,
+    ready: &mut ReadyQueue,
+    defer_free: &mut DeferFree,
+) -> Result<String, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words[..] {
+        ["REBIND", fd, backend] => {
+            let i = g
+                .backends
+                .iter()
+                .position(|b| b.name == backend)
+                .ok_or_else(|| format!("no backend {}", backend))?;
+            let ctx = control_conn(g, fd)?;
+            start_rebind(g, &ctx, i, ready)?;
+            Ok(format!("OK rebinding client_fd {} to {}", fd, backend))
+        }
+        ["HALFCLOSE", fd, dir] => {
+            let ctx = control_conn(g, fd)?;
+            let done = ctx.borrow_mut().half_close(dir)?;
+            if let Some(reason) = done {
+                defer_shutdown(g, defer_free, &ctx, reason);
+                return Ok(format!("OK client_fd {} closed", fd));
+            }
+            Ok(format!("OK client_fd {} {} shut", fd, dir))
+        }
+        ["LISTEN", "enable", addr] => set_listener(g, addr, true),
+        ["LISTEN", "disable", addr] => set_listener(g, addr, false),
+        ["PAUSE", fd] => {
+            control_conn(g, fd)?.borrow_mut().pause()?;
+            Ok(format!("OK client_fd {} paused", fd))
+        }
+        ["RESUME", fd] => {
+            control_conn(g, fd)?.borrow_mut().resume()?;
+            Ok(format!("OK client_fd {} resumed", fd))
+        }
+        // OK with the count, then one JSON object per line
+        ["CONNS"] => {
+            let conns: Vec<_> = g
+                .conns
+                .values()
+                .filter_map(Weak::upgrade)
+                .map(|ctx| ctx.borrow().json_state(&g.backends))
+                .collect();
+            let mut reply = format!("OK {}", conns.len());
+            for conn in conns {
+                reply.push('\n');
+                reply += &conn;
+            }
+            Ok(reply)
+        }
+        // OK with the count, then one JSON object per backend, -d ones first
+        ["BACKENDS"] => {
+            let mut reply = format!("OK {}", g.backends.len());
+            for (backend, stats) in g.backends.iter().zip(backend_stats(g)) {
+                reply += &format!(
+                    "\n{{\"backend\":{},\"connects\":{},\"active\":{},\"connect_failures\":{},\"bytes_in\":{},\"bytes_out\":{},\"down\":{}}}",
+                    json_backend(Some(backend)),
+                    stats.connects,
+                    stats.active,
+                    stats.connect_failures,
+                    stats.bytes_in,
+                    stats.bytes_out,
+                    stats.down
+                );
+            }
+            Ok(reply)
+        }
+        // the load -shed and -shed-rate go by and what they dropped
+        ["SHED"] => {
+            let now = Instant::now();
+            let rate = g.accept_rate.rate(now);
+            Ok(format!(
+                "OK {{\"connections\":{},\"accept_rate\":{},\"fraction\":{:.3},\"shed\":{}}}",
+                g.conns.len(),
+                rate,
+                shed_fraction(g, rate),
+                g.rejects.get("shed").unwrap_or(&0)
+            ))
+        }
+        [] => Err("empty command".to_string()),
+        _ => Err(format!("unknown command {}", words[0])),
+    }
+}
+
+fn start_rebind(
+    g: &Global,
+    ctx: &Rc<RefCell<Context>>,
+    backend: usize,
+    ready: &mut ReadyQueue,
+) -> Result<(), String> {
+    let mut c = ctx.borrow_mut();
+    if c.state != State::Streaming {
+        return Err(format!("client_fd {} is not streaming", c.client_fd));
+    }
+    if c.paused {
+        return Err(format!("client_fd {} is paused", c.client_fd));
+    }
+    log!(
+        "conn {}: rebind client_fd {} to {}",
+        c.id,
+        c.client_fd,
+        g.backends[backend].name
+    );
+    c.state = State::Rebinding;
+    c.rebind = Some(Rebind {
+        backend,
+        shut: false,
+        ready: false,
+    });
+    // no edge announces what is already buffered
+    if !c.ready {
+        c.ready = true;
+        ready.push_back(ctx.clone());
+    }
+    Ok(())
+}
+
+// the old backend is drained, its fd and PollDesp go and the context starts
+// over on the new backend
+fn finish_rebind(g: &mut Global, ctx: &Rc<RefCell<Context>>) -> Result<(), CloseReason> {
+    let backend = {
+        let mut c = ctx.borrow_mut();
+        let backend = match c.rebind.take() {
+            Some(Rebind {
+                backend,
+                ready: true,
+                ..
+            }) if c.state == State::Rebinding => backend,
+            _ => return Ok(()),
+        };
+        release_backend(g, &mut c);
+        if let Err(e) = epoll_del(c.backend_fd) {
+            log!("epoll_del {} failed: {}", c.backend_fd, e);
+        }
+        unsafe { libc::close(c.backend_fd) };
+        mem::drop(unsafe { Box::from_raw(c.out_pd as *mut PollDesp) });
+        c.out_pd = 0;
+        c.backend_fd = -1;
+        c.backend_tls = None;
+        c.state = State::Connecting;
+        backend
+    };
+    connect_to(g, ctx, backend).map_err(CloseReason::ConnectFailed)?;
+    // client data that came in meanwhile has no edge of its own
+    ctx.borrow_mut().copy_from()
+}
+
+// queues the context for what has to happen outside of its own events
+fn after_copy(
+    ctx: &Rc<RefCell<Context>>,
+    ready: &mut ReadyQueue,
+    defer_rebind: &mut Vec<Rc<RefCell<Context>>>,
+) {
+    let mut c = ctx.borrow_mut();
+    if c.wants_turn() {
+        c.ready = true;
+        ready.push_back(ctx.clone());
+    }
+    if c.rebind_ready() {
+        defer_rebind.push(ctx.clone());
+    }
+}
+
+// -retry-reset: a backend that reset the connection before a byte went
+// either way is swapped for the next one through the rebind path, once per
+// connection. Client data already buffered goes to the new backend
+fn retry_reset(
+    g: &mut Global,
+    ctx: &Rc<RefCell<Context>>,
+    defer_rebind: &mut Vec<Rc<RefCell<Context>>>,
+) -> bool {
+    let mut c = ctx.borrow_mut();
+    if !g.config.retry_reset
+        || c.retried
+        || c.state != State::Streaming
+        || c.in_buf.moved != 0
+        || c.out_buf.moved != 0
+    {
+        return false;
+    }
+    // a routed client stays with the backend of its host
+    let backend = match c.backend {
+        Some(i) if i >= g.pool => i,
+        Some(i) if g.pool > 1 => {
+            let mut j = g.pick_backend();
+            if j == i {
+                j = g.pick_backend();
+            }
+            j
+        }
+        _ => g.pick_backend(),
+    };
+    log!(
+        "conn {}: backend_fd {} reset, retrying on {}",
+        c.id,
+        c.backend_fd,
+        g.backends[backend].name
+    );
+    c.retried = true;
+    c.state = State::Rebinding;
+    c.rebind = Some(Rebind {
+        backend,
+        shut: true,
+        ready: true,
+    });
+    defer_rebind.push(ctx.clone());
+    true
+}
+
+// runs on every timer tick, the expired connections go down with the batch
+fn expire_timeouts(g: &mut Global, defer_free: &mut DeferFree) {
+    let now = Instant::now();
+    let expired: Vec<_> = g
+        .conns
+        .values()
+        .filter_map(Weak::upgrade)
+        .filter_map(|ctx| {
+            let reason = {
+                let mut c = ctx.borrow_mut();
+                if c.state == State::Closing {
+                    return None;
+                } else if c.connect_expired(&g.backends, now) {
+                    log!("conn {}: backend_fd {} connect timeout", c.id, c.backend_fd);
+                    CloseReason::ConnectFailed(libc::ETIMEDOUT)
+                } else if c.routing
+                    && g.config
+                        .header_timeout
+                        .is_some_and(|timeout| now - c.start >= timeout)
+                {
+                    log!("conn {}: client_fd {} header timeout", c.id, c.client_fd);
+                    CloseReason::HeaderTimeout
+                } else if !c.paused && c.idle_expired(&g.config, now) {
+                    log!("conn {}: client_fd {} idle timeout", c.id, c.client_fd);
+                    CloseReason::IdleTimeout
+                } else if let Some(dir) = c.stalled(&g.config, now).filter(|_| !c.paused) {
+                    log!(
+                        "conn {}: client_fd {} stalled, {} bytes for {} not taken",
+                        c.id,
+                        c.client_fd,
+                        if dir == "in" {
+                            c.in_buf.buffered
+                        } else {
+                            c.out_buf.buffered
+                        },
+                        dir
+                    );
+                    CloseReason::Stalled
+                } else {
+                    return None;
+                }
+            };
+            Some((ctx, reason))
+        })
+        .collect();
+    for (ctx, reason) in expired {
+        defer_shutdown(g, defer_free, &ctx, reason);
+    }
+    if let Some(ttl) = g.config.sticky {
+        g.sticky.retain(|_, &mut (_, at)| now - at < ttl);
+    }
+}
+
+fn exit_summary(g: &Global) {
+    for (reason, n) in &g.close_reasons {
+        log!("closed {}: {}", reason, n);
+    }
+    for (why, n) in &g.rejects {
+        log!("rejected {}: {}", why, n);
+    }
+    g.connect_latency.dump("backend connect latency");
+    g.first_byte_out.dump("first byte to client");
+    // live connections are sampled as they stand
+    let mut traffic = g.traffic.clone();
+    let (mut full_in, mut full_out) = (g.full_in, g.full_out);
+    for ctx in g.conns.values().filter_map(Weak::upgrade) {
+        let mut ctx = ctx.borrow_mut();
+        if g.config.tcp_info {
+            ctx.sample_wire();
+        }
+        traffic.add(ctx.in_buf.moved, ctx.out_buf.moved, ctx.wire);
+        full_in.add(ctx.in_buf.full);
+        full_out.add(ctx.out_buf.full);
+    }
+    traffic.dump();
+    full_in.dump("in");
+    full_out.dump("out");
+    for (backend, stats) in g.backends.iter().zip(backend_stats(g)) {
+        log!(
+            "backend {}: connects {} active {} connect failures {} bytes in {} out {}",
+            backend.name,
+            stats.connects,
+            stats.active,
+            stats.connect_failures,
+            stats.bytes_in,
+            stats.bytes_out
+        );
+    }
+}
+
+// the listen fds handed over by the process that was upgraded, in the order
+// of the listeners, and the pid of that process
+const LISTEN_FDS_VAR: &str = "TCPPROXY_LISTEN_FDS";
+const UPGRADE_PID_VAR: &str = "TCPPROXY_UPGRADE_PID";
+
+fn inherited_listen_fds(listeners: usize) -> Result<Option<Vec<i32>>, String> {
+    let value = match env::var(LISTEN_FDS_VAR) {
+        Ok(value) => value,
+        Err(_) => return Ok(None),
+    };
+    // an upgrade of this process hands over a list of its own
+    env::remove_var(LISTEN_FDS_VAR);
+    let fds = value
+        .split(',')
+        .map(|fd| fd.parse::<i32>().map_err(|_| format!("bad fd {:?}", fd)))
+        .collect::<Result<Vec<_>, _>>()?;
+    if fds.len() != listeners {
+        return Err(format!(
+            "{} fds for {} listeners, the listeners changed across the upgrade",
+            fds.len(),
+            listeners
+        ));
+    }
+    for &fd in &fds {
+        sys::set_cloexec(fd).map_err(|e| format!("fd {}: {}", fd, e))?;
+    }
+    Ok(Some(fds))
+}
+
+// -mark and -lmark fail the start when the marks cannot be set
+fn check_marks(config: &Config) -> Result<(), String> {
+    for &(name, mark) in &[("-mark", config.mark), ("-lmark", config.listen_mark)] {
+        let mark = match mark {
+            Some(mark) => mark,
+            None => continue,
+        };
+        match net::sock_opt_allowed(&net::SockOpt::mark(mark)) {
+            Ok(()) => {}
+            Err(libc::EPERM) => return Err(format!("{} requires root or CAP_NET_ADMIN", name)),
+            Err(e) => return Err(format!("{} {} failed: {}", name, mark, e)),
+        }
+    }
+    Ok(())
+}
+
+fn md5_unavailable(e: i32) -> String {
+    match e {
+        libc::ENOPROTOOPT => {
+            "-md5: the kernel has no TCP MD5 signatures (CONFIG_TCP_MD5SIG)".to_string()
+        }
+        e => format!("-md5: TCP MD5 signatures failed: {}", e),
+    }
+}
+
+// the fds of -inherit-fds with their addresses, each one has to be a
+// listening TCP socket. Through an upgrade they keep their numbers
+fn adopt_listen_fds(fds: &[i32]) -> Result<Vec<(i32, SocketAddr)>, String> {
+    fds.iter()
+        .enumerate()
+        .map(|(i, &fd)| {
+            if fds[..i].contains(&fd) {
+                return Err(format!("-inherit-fds {}: given twice", fd));
+            }
+            let addr = net::listening_addr(fd).map_err(|e| {
+                let what = match e {
+                    libc::EBADF => "not open",
+                    libc::ENOTSOCK => "not a socket",
+                    _ => "not a listening TCP socket",
+                };
+                format!("-inherit-fds {}: {}", fd, what)
+            })?;
+            Ok((fd, addr))
+        })
+        .collect()
+}
+
+// tells the process that was upgraded the listeners are served here now
+fn upgrade_ready() {
+    let pid = match env::var(UPGRADE_PID_VAR) {
+        Ok(pid) => pid,
+        Err(_) => return,
+    };
+    env::remove_var(UPGRADE_PID_VAR);
+    // the old process may have been stopped and the pid taken by another
+    let ppid = unsafe { libc::getppid() };
+    if pid.parse() == Ok(ppid) {
+        unsafe { libc::kill(ppid, libc::SIGUSR2) };
+    }
+}
+
+// starts the new binary on the listen fds, the pid of the new process on
+// success. This process keeps accepting until the new one is ready
+fn start_upgrade(
+    exe: Option<&PathBuf>,
+    listeners: &[Listener],
+    health_fd: Option<i32>,
+) -> Option<i32> {
+    let exe = match exe {
+        Some(exe) => exe,
+        None => {
+            log!("upgrade failed: path of the binary unknown");
+            return None;
+        }
+    };
+    // the -health listener goes last, disabled listeners are handed on as
+    // well and accept again in the new process
+    let fds: Vec<i32> = listeners.iter().map(|l| l.fd).chain(health_fd).collect();
+    let value = fds
+        .iter()
+        .map(|fd| fd.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let vars = [
+        (LISTEN_FDS_VAR, value),
+        (UPGRADE_PID_VAR, process::id().to_string()),
+    ];
+    match sys::spawn_self(exe, &fds, &vars) {
+        Ok(pid) => {
+            log!("upgrade: started pid {}", pid);
+            Some(pid)
+        }
+        Err(e) => {
+            log!("upgrade failed: {}", e);
+            None
+        }
+    }
+}
+
+// leaves the listen fds and the control socket to the new process. The
+// -health listener is shared with it and stays, probes get a 503 from here
+fn stop_accept(g: &mut Global, control_fd: Option<i32>) {
+    g.draining = true;
+    if !g.cooling {
+        for l in g.listeners.iter().filter(|l| !l.disabled) {
+            unregister(l.fd, "listener");
+        }
+    }
+    // the socket path is the new process's now, it is not removed on exit
+    if let Some(fd) = control_fd {
+        unregister(fd, "control socket");
+        unsafe { libc::close(fd) };
+    }
+}
+
+fn watch_listener(g: &Global, i: usize) -> SysResult<()> {
+    let what = format_args!("listener {}", g.listeners[i].addr);
+    epoll_register(g.listeners[i].fd, 1, Token::Listener(i).into(), what)
+}
+
+// a listener that cannot be watched again is left out, the others carry
+// on. Its backlog raises an event as it is added
+fn watch_listeners(g: &Global) {
+    for i in 0..g.listeners.len() {
+        if !g.listeners[i].disabled {
+            if let Err(e) = watch_listener(g, i) {
+                log!("epoll_add listener {} failed: {}", g.listeners[i].fd, e);
+            }
+        }
+    }
+}
+
+// the errors of accept that end with the backlog kept for later
+const OUT_OF_FDS: [i32; 4] = [libc::EMFILE, libc::ENFILE, libc::ENOBUFS, libc::ENOMEM];
+
+// the errors of accept that are about a single connection, one that was
+// reset in the backlog or refused by a firewall, and the backlog goes on
+const ACCEPT_TRANSIENT: [i32; 4] = [libc::ECONNABORTED, libc::EPROTO, libc::EPERM, libc::EINTR];
+
+// accept ran out of fds or socket memory. Edge triggered listeners would
+// be retried on every event with the backlog untouched, instead they leave
+// the epoll set until the -accept-cooldown is over and connections closing
+// meanwhile have freed some
+fn cool_accept(g: &mut Global, e: i32) {
+    if g.cooling {
+        return;
+    }
+    let cooldown = g.config.accept_cooldown;
+    log!("accept failed: {}, not accepting for {:?}", e, cooldown);
+    for l in g.listeners.iter().filter(|l| !l.disabled) {
+        unregister(l.fd, "listener");
+    }
+    g.cooling = true;
+    let deadline = Instant::now() + cooldown;
+    if let Err(e) = g.timers.add(deadline, TimerEvent::AcceptCooldown) {
+        // nothing would end the cooldown, accepting goes on as before
+        log!("timer failed: {}, accepting again", e);
+        g.cooling = false;
+        watch_listeners(g);
+    }
+}
+
+// LISTEN enable|disable addr, a disabled listener leaves the epoll set and
+// its clients wait in the backlog. While draining only the flag changes
+fn set_listener(g: &mut Global, addr: &str, enable: bool) -> Result<String, String> {
+    let addr: SocketAddr = addr
+        .parse()
+        .map_err(|_| format!("bad listen address {}", addr))?;
+    let i = g
+        .listeners
+        .iter()
+        .position(|l| net::canonical(l.addr) == net::canonical(addr))
+        .ok_or_else(|| format!("no listener {}", addr))?;
+    let state = if enable { "enabled" } else { "disabled" };
+    if g.listeners[i].disabled != enable {
+        return Err(format!("listener {} is {} already", addr, state));
+    }
+    // a cooling listener is watched again with the others
+    if !g.draining && !g.cooling {
+        if enable {
+            watch_listener(g, i).map_err(|e| format!("epoll_add failed: {}", e))?;
+        } else {
+            epoll_del(g.listeners[i].fd).map_err(|e| format!("epoll_del failed: {}", e))?;
+        }
+    }
+    g.listeners[i].disabled = !enable;
+    log!("listener {} {}", addr, state);
+    Ok(format!("OK listener {} {}", addr, state))
+}
+
+// takes the listen fds back after the new process went away, along with the
+// control socket
+fn resume_accept(g: &mut Global) -> Option<i32> {
+    g.draining = false;
+    if !g.cooling {
+        watch_listeners(g);
+    }
+    if g.config.control.is_empty() {
+        return None;
+    }
+    match control::listen(&g.config.control) {
+        Ok(fd) => match epoll_register(fd, 1, Token::Control.into(), format_args!("control")) {
+            Ok(()) => Some(fd),
+            Err(e) => {
+                log!("epoll_add control socket {} failed: {}", fd, e);
+                unsafe { libc::close(fd) };
+                None
+            }
+        },
+        Err(e) => {
+            log!("control socket {} failed: {}", g.config.control, e);
+            None
+        }
+    }
+}
+
+// a client fd, a backend fd and the two ends of two pipes
+const FDS_PER_CONN: u64 = 6;
+
+// raises the fd limit for -nofile and warns when it is too low to carry a
+// useful number of connections
+fn check_nofile(config: &Config) {
+    if config.nofile != 0 {
+        match sys::set_nofile(config.nofile) {
+            Ok(soft) if soft < config.nofile => {
+                log!("fd limit raised to the hard limit {}", soft)
+            }
+            Ok(_) => {}
+            Err(e) => log!("setrlimit {} failed: {}", config.nofile, e),
+        }
+    }
+    let (soft, hard) = match sys::nofile() {
+        Ok(limit) => limit,
+        Err(e) => {
+            log!("getrlimit failed: {}", e);
+            return;
+        }
+    };
+    log!("fd limit soft {} hard {}", soft, hard);
+    // a -mirror tap brings a socket and a pipe of its own, a -sink client
+    // is just its socket
+    let per_conn = if config.sink {
+        1
+    } else if config.mirror.is_empty() {
+        FDS_PER_CONN
+    } else {
+        FDS_PER_CONN + 3
+    };
+    if soft / per_conn < 1024 {
+        log!(
+            "warning: fd limit {} carries about {} connections, raise it with -nofile or ulimit -n",
+            soft,
+            soft / per_conn
+        );
+    }
+}
+
+fn main() {
+    let config = parse_args(env::args().skip(1));
+    if config.check || config.print_config {
+        match check_config(&config, config.check) {
+            Ok(()) => process::exit(0),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let mut listen_addrs: Vec<SocketAddr> = config
+        .listeners()
+        .into_iter()
+        .map(|(addr, _, _)| {
+            net::resolve_first(addr, true).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                process::exit(1);
+            })
+        })
+        .collect();
+    let backends = resolve_backends(&config).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let tls_config = if config.tls_listen_addrs.is_empty() {
+        None
+    } else {
+        Some(
+            tls::load_server_config(&config.tls_cert, &config.tls_key).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                process::exit(1);
+            }),
+        )
+    };
+
+    let backend_tls = if config.backend_tls {
+        let client_config = tls::load_client_config(&config.backend_ca, config.backend_insecure)
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                process::exit(1);
+            });
+        Some(client_config)
+    } else {
+        None
+    };
+
+    sys::init(config.verbose, sys::syslog_facility(&config.syslog));
+    check_nofile(&config);
+    if let Err(e) = check_marks(&config) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+
+    // the binary is looked up again on SIGUSR2, by then it may be replaced
+    let exe = env::current_exe().ok();
+    let health = !config.health.is_empty();
+    let adopted = adopt_listen_fds(&config.inherit_fds).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let inherited = inherited_listen_fds(listen_addrs.len() + adopted.len() + health as usize)
+        .unwrap_or_else(|e| {
+            eprintln!("{}: {}", LISTEN_FDS_VAR, e);
+            process::exit(1);
+        });
+    let mut listen_fds = Vec::new();
+    for (i, ((addr, tls, family), listen_addr)) in config
+        .listeners()
+        .into_iter()
+        .zip(&listen_addrs)
+        .enumerate()
+    {
+        if let Some(fds) = &inherited {
+            register_or_exit(fds[i], Token::Listener(listen_fds.len()), "listener");
+            listen_fds.push((fds[i], tls, family));
+            continue;
+        }
+        let listen_fd = net::listen_tcp(listen_addr, config.listen_device(), config.transparent)
+            .unwrap_or_else(|e| {
+                if e == libc::EPERM && config.transparent {
+                    eprintln!(
+                        "listen {} failed: -transparent requires root or CAP_NET_ADMIN",
+                        addr
+                    );
+                } else if e == libc::EPERM && config.listen_device().is_some() {
+                    eprintln!(
+                        "listen {} failed: binding to device {} requires root or CAP_NET_RAW",
+                        addr, config.listen_device
+                    );
+                } else {
+                    eprintln!("listen {} failed: {}", addr, e);
+                }
+                process::exit(1);
+            });
+        register_or_exit(listen_fd, Token::Listener(listen_fds.len()), "listener");
+        listen_fds.push((listen_fd, tls, family));
+    }
+    // plaintext listeners after the configured ones, an upgrade hands them
+    // on in that order
+    for (fd, addr) in adopted {
+        for r in &[sys::set_nonblock(fd), sys::set_cloexec(fd)] {
+            if let Err(e) = r {
+                eprintln!("-inherit-fds {}: {}", fd, e);
+                process::exit(1);
+            }
+        }
+        register_or_exit(fd, Token::Listener(listen_fds.len()), "listener");
+        listen_fds.push((fd, false, Family::Both));
+        listen_addrs.push(addr);
+        if inherited.is_none() {
+            log!("adopted fd {} listening on {}", fd, addr);
+        }
+    }
+    // accepted sockets take the mark of their listener
+    if let Some(mark) = config.listen_mark {
+        let opt = net::SockOpt::mark(mark);
+        for &(fd, _, _) in &listen_fds {
+            if let Err(e) = opt.set(fd) {
+                eprintln!("-lmark {} on fd {} failed: {}", mark, fd, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    // SIGINT/SIGTERM, SIGUSR2 and SIGCHLD of an upgrade come in through the
+    // event loop
+    let signal_fd =
+        sys::signal_fd(&[libc::SIGINT, libc::SIGTERM, libc::SIGUSR2, libc::SIGCHLD]).unwrap();
+    register_or_exit(signal_fd, Token::Signal, "signalfd");
+
+    // everything time based runs off one timerfd
+    let mut timers = timer::Timers::new().unwrap();
+    register_or_exit(timers.fd(), Token::Timer, "timerfd");
+    // slow connects and idle connections are looked for on a tick no coarser
+    // than a second
+    if let Some(timeout) = config.timeouts().min() {
+        let tick = timeout.min(Duration::from_secs(1));
+        timers
+            .add(Instant::now() + tick, TimerEvent::Tick(tick))
+            .unwrap();
+    }
+    if let Some(delay) = config.accept_delay {
+        log!("accept delay {:?}, for testing only", delay);
+    }
+    if let Some(runtime) = config.runtime {
+        timers
+            .add(Instant::now() + runtime, TimerEvent::Runtime)
+            .unwrap();
+    }
+
+    // the -health listener comes after the others in an upgrade
+    let health_fd = if health {
+        let fd = match &inherited {
+            Some(fds) => fds[listen_addrs.len()],
+            None => net::resolve_first(&config.health, true)
+                .and_then(|addr| net::listen_tcp(&addr, None, false).map_err(|e| e.to_string()))
+                .unwrap_or_else(|e| {
+                    eprintln!("health {} failed: {}", config.health, e);
+                    process::exit(1);
+                }),
+        };
+        register_or_exit(fd, Token::Health, "health listener");
+        Some(fd)
+    } else {
+        None
+    };
+
+    let mut control_fd = if config.control.is_empty() {
+        None
+    } else {
+        let fd = control::listen(&config.control).unwrap_or_else(|e| {
+            eprintln!("control socket {} failed: {}", config.control, e);
+            process::exit(1);
+        });
+        register_or_exit(fd, Token::Control, "control socket");
+        Some(fd)
+    };
+
+    if inherited.is_some() {
+        log!("listen ok, {} fds inherited", listen_fds.len());
+    } else {
+        log!("listen ok");
+    }
+
+    if !config.md5_key.is_empty() {
+        if let Err(e) = net::md5sig_available() {
+            eprintln!("{}", md5_unavailable(e));
+            process::exit(1);
+        }
+    }
+    let src_ip = config.src_addr.map(|addr| addr.ip());
+    let src_ports = config.src_ports.map(|(lo, hi)| net::PortRange::new(lo, hi));
+    let mptcp = config.mptcp
+        && match net::mptcp_available() {
+            Ok(()) => true,
+            Err(e) => {
+                log!("mptcp unavailable, backends get plain tcp: {}", e);
+                false
+            }
+        };
+    let mirror = if config.mirror.is_empty() {
+        None
+    } else {
+        Some(
+            net::resolve_first(&config.mirror, false).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                process::exit(1);
+            }),
+        )
+    };
+    let upstream = config.upstream().map(|proxy| {
+        let addr = net::resolve_first(proxy, false).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        });
+        let proto = if config.socks5.is_empty() {
+            let auth = config.http_connect_auth.clone();
+            upstream::Proto::HttpConnect {
+                auth: if auth.is_empty() { None } else { Some(auth) },
+            }
+        } else {
+            let auth = config.socks5_auth.find(':').map(|i| {
+                (
+                    config.socks5_auth[..i].to_string(),
+                    config.socks5_auth[i + 1..].to_string(),
+                )
+            });
+            upstream::Proto::Socks5 { auth }
+        };
+        (addr, proto)
+    });
+    let mut g = Global {
+        // the original destination of -redirect comes after the -d backends
+        pool: backends.len() - config.routes.len() - config.redirect as usize,
+        routes: config
+            .routes
+            .iter()
+            .enumerate()
+            .map(|(j, (name, _))| (name.clone(), backends.len() - config.routes.len() + j))
+            .collect(),
+        sticky: HashMap::new(),
+        config,
+        listeners: listen_fds
+            .into_iter()
+            .zip(listen_addrs)
+            .map(|((fd, tls, family), addr)| Listener {
+                fd,
+                tls,
+                family,
+                addr,
+                disabled: false,
+            })
+            .collect(),
+        draining: false,
+        cooling: false,
+        backend_stats: vec![stats::BackendStats::default(); backends.len()],
+        backends,
+        tls_config,
+        backend_tls,
+        src_ip,
+        src_ports,
+        mptcp,
+        upstream,
+        mirror,
+        connecting: 0,
+        connect_latency: stats::Histogram::new(),
+        first_byte_out: stats::Histogram::new(),
+        close_reasons: BTreeMap::new(),
+        rejects: BTreeMap::new(),
+        traffic: stats::Traffic::default(),
+        full_in: stats::PipeFull::default(),
+        full_out: stats::PipeFull::default(),
+        pending: VecDeque::new(),
+        last_id: 0,
+        timers,
+        delayed: 0,
+        accept_rate: stats::AcceptRate::new(Instant::now()),
+        shed_credit: 0.0,
+        conns: BTreeMap::new(),
+        backend_socks: HashMap::new(),
+        clients: HashMap::new(),
+    };
+    let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; g.config.max_events];
+    // drained at the end of every batch, their capacity is kept across batches.
+    // defer_shutdown queues a context once, on its move to Closing
+    let mut defer_free: DeferFree = Vec::new();
+    let mut defer_rebind = Vec::new();
+    // connections with work left that no edge will announce, stopped on
+    // -fairbytes or just put into Rebinding. Served after the next wait
+    let mut ready = ReadyQueue::new();
+    // the pid of the new process after SIGUSR2, this one drains once that
+    // reports ready
+    let mut upgrade = None;
+    // draining after -runtime, there is no new process to take over
+    let mut stopping = false;
+    if inherited.is_some() {
+        upgrade_ready();
+    }
+    loop {
+        debug!("polling events");
+        let res = syscall!(libc::epoll_wait(
+            epoll_fd(),
+            events.as_mut_ptr(),
+            events.len() as i32,
+            if ready.is_empty() { -1 } else { 0 }
+        ));
+        let n = match res {
+            Ok(n) => n,
+            Err(e) => {
+                if e == libc::EINTR {
+                    continue;
+                }
+                panic!("epoll_wait failed: {}", e);
+            }
+        };
+        debug!("epoll {} events raised", n);
+        // those queued while handling this batch wait for the next one
+        let turns = ready.len();
+        for ev in events.iter().take(n as usize) {
+            let pd = match Token::from(ev.u64) {
+                Token::Signal => {
+                    loop {
+                        let (sig, from) = match sys::read_signal(signal_fd) {
+                            Ok(Some(signal)) => signal,
+                            Ok(None) => break,
+                            Err(libc::EINTR) => continue,
+                            Err(e) => {
+                                log!("signalfd read failed: {}", e);
+                                break;
+                            }
+                        };
+                        match sig {
+                            libc::SIGUSR2 if upgrade == Some(from) => {
+                                if !g.draining {
+                                    log!(
+                                        "upgrade: pid {} ready, draining {} connections",
+                                        from,
+                                        g.conns.len()
+                                    );
+                                    stop_accept(&mut g, control_fd.take());
+                                }
+                            }
+                            libc::SIGUSR2 if upgrade.is_some() => {
+                                log!("upgrade already in progress");
+                            }
+                            libc::SIGUSR2 => {
+                                upgrade = start_upgrade(exe.as_ref(), &g.listeners, health_fd)
+                            }
+                            libc::SIGCHLD => {
+                                let pid = match upgrade {
+                                    Some(pid) => pid,
+                                    None => continue,
+                                };
+                                let status =
+                                    match sys::reap(pid).unwrap_or_else(|e| Some(e.to_string())) {
+                                        Some(status) => status,
+                                        None => continue,
+                                    };
+                                upgrade = None;
+                                if g.draining && !stopping {
+                                    log!("upgrade: pid {} {}, accepting again", pid, status);
+                                    control_fd = resume_accept(&mut g);
+                                } else {
+                                    log!("upgrade failed: pid {} {}", pid, status);
+                                }
+                            }
+                            _ => {
+                                log!("exit on signal {}", sig);
+                                if control_fd.is_some() {
+                                    let _ = fs::remove_file(&g.config.control);
+                                }
+                                exit_summary(&g);
+                                process::exit(0);
+                            }
+                        }
+                    }
+                    continue;
+                }
+                Token::Timer => {
+                    let now = Instant::now();
+                    for (deadline, event) in g.timers.expired(now) {
+                        match event {
+                            // the listeners go as for an upgrade, the control
+                            // socket stays until the exit
+                            TimerEvent::Runtime => {
+                                log!("runtime over, draining {} connections", g.conns.len());
+                                if !g.draining {
+                                    stop_accept(&mut g, None);
+                                }
+                                stopping = true;
+                                let deadline = now + g.config.drain_timeout();
+                                if let Err(e) = g.timers.add(deadline, TimerEvent::DrainTimeout) {
+                                    log!("timer failed: {}, the drain has no timeout", e);
+                                }
+                            }
+                            event => timer_fired(&mut g, &mut defer_free, deadline, event, now),
+                        }
+                    }
+                    continue;
+                }
+                Token::Sink(fd) => {
+                    drain_sink(&mut g, fd);
+                    continue;
+                }
+                Token::Health => {
+                    loop {
+                        match control::accept(health_fd.unwrap()) {
+                            Ok(fd) => {
+                                let token = Token::HealthClient(fd);
+                                watch_client(&mut g, fd, token, http::HEAD_TIMEOUT);
+                            }
+                            Err(e) => {
+                                if e != libc::EAGAIN {
+                                    log!("health accept failed: {}", e);
+                                }
+                                break;
+                            }
+                        }
+                    }
+                    continue;
+                }
+                Token::HealthClient(fd) => {
+                    if client_request(&mut g, fd, http::MAX_HEAD, http::head_complete).is_some() {
+                        let response = http::health_response(healthy(&g));
+                        reply_client(&mut g, fd, Token::HealthClient(fd), response);
+                    }
+                    flush_client(&mut g, fd);
+                    continue;
+                }
+                Token::Control => {
+                    loop {
+                        match control::accept(control_fd.unwrap()) {
+                            Ok(fd) => {
+                                let token = Token::ControlClient(fd);
+                                watch_client(&mut g, fd, token, control::LINE_TIMEOUT);
+                            }
+                            Err(e) => {
+                                if e != libc::EAGAIN {
+                                    log!("control accept failed: {}", e);
+                                }
+                                break;
+                            }
+                        }
+                    }
+                    continue;
+                }
+                Token::ControlClient(fd) => {
+                    let request =
+                        client_request(&mut g, fd, control::MAX_LINE, control::line_complete);
+                    if let Some(request) = request {
+                        let line = control::first_line(&request);
+                        let msg = match control_command(&mut g, &line, &mut ready, &mut defer_free)
+                        {
+                            Ok(msg) => msg,
+                            Err(msg) => format!("ERR {}", msg),
+                        };
+                        let output = format!("{}\n", msg).into_bytes();
+                        reply_client(&mut g, fd, Token::ControlClient(fd), output);
+                    }
+                    flush_client(&mut g, fd);
+                    continue;
+                }
+                Token::Listener(i) => {
+                    let l = &g.listeners[i];
+                    let (listen_fd, tls, family, listener) = (l.fd, l.tls, l.family, l.addr);
+                    loop {
+                        match net::accept(listen_fd) {
+                            Ok((fd, addr)) => {
+                                log!("accept client_fd {} on {}", fd, listener);
+                                if family != Family::Both && !family_allowed(fd, addr, family) {
+                                    turn_away(&mut g, fd, tls, Reject::Family);
+                                    continue;
+                                }
+                                if stale_accept(&g, fd) {
+                                    turn_away(&mut g, fd, tls, Reject::AcceptStale);
+                                    continue;
+                                }
+                                if let Some(origin) = backend_loop(&g, addr) {
+                                    turn_away(&mut g, fd, tls, Reject::BackendLoop);
+                                    let reason = CloseReason::Error(libc::ELOOP);
+                                    defer_shutdown(&mut g, &mut defer_free, &origin, reason);
+                                    continue;
+                                }
+                                if shed(&mut g, fd) {
+                                    turn_away(&mut g, fd, tls, Reject::Shed);
+                                    continue;
+                                }
+                                match g.config.accept_delay {
+                                    Some(delay) => {
+                                        delay_client(&mut g, delay, fd, addr, listener, tls)
+                                    }
+                                    None => serve_client(&mut g, fd, addr, listener, tls),
+                                }
+                            }
+                            Err(libc::EAGAIN) => break,
+                            Err(e) if OUT_OF_FDS.contains(&e) => {
+                                cool_accept(&mut g, e);
+                                break;
+                            }
+                            Err(e) if ACCEPT_TRANSIENT.contains(&e) => {
+                                log!("accept failed: {}", e);
+                            }
+                            Err(e) => {
+                                log!("accept failed: {}", e);
+                                break;
+                            }
+                        };
+                    }
+                    continue;
+                }
+                Token::Conn(pd) => unsafe { &mut *pd },
+            };
+            // a PAUSE earlier in the batch leaves the events of the rest
+            if pd.ctx.borrow().state == State::Closing || pd.ctx.borrow().paused {
+                continue;
+            }
+            let mut free = None;
+            let mut events = ev.events;
+            // a stripped route line may leave no data to start the connect
+            let mut routed = false;
+            if pd.who == 0 && pd.ctx.borrow().routing {
+                match route_client(&g, &pd.ctx) {
+                    Ok(false) => continue,
+                    // the head has no edge of its own left
+                    Ok(true) => {
+                        events |= libc::EPOLLIN as u32;
+                        routed = g.config.route_prefix && !g.config.lazy;
+                    }
+                    Err(reason) => {
+                        defer_shutdown(&mut g, &mut defer_free, &pd.ctx, reason);
+                        continue;
+                    }
+                }
+            }
+            if pd.who == 1 && pd.ctx.borrow().state == State::Connecting {
+                if ev.events & (libc::EPOLLOUT | libc::EPOLLERR | libc::EPOLLHUP) as u32 == 0 {
+                    continue;
+                }
+                let res = pd.ctx.borrow_mut().finish_connect();
+                match res {
+                    Ok(false) => {
+                        let ctx = pd.ctx.borrow();
+                        debug!(
+                            "conn {}: backend_fd {} still connecting",
+                            ctx.id, ctx.backend_fd
+                        );
+                        continue;
+                    }
+                    Ok(true) => {
+                        let ctx = pd.ctx.borrow();
+                        let mptcp = match g.mptcp {
+                            true if net::is_mptcp(ctx.backend_fd).unwrap_or(false) => ", mptcp",
+                            true => ", mptcp fell back to tcp",
+                            false => "",
+                        };
+                        log!(
+                            "conn {}: backend_fd {} connected{}",
+                            ctx.id,
+                            ctx.backend_fd,
+                            mptcp
+                        );
+                        g.connect_latency.record(ctx.connect_start.elapsed());
+                        g.connecting -= 1;
+                        if let Some(i) = ctx.backend {
+                            g.backend_stats[i].down = false;
+                        }
+                    }
+                    Err(e) => {
+                        log!("conn {}: connect backend failed: {}", pd.ctx.borrow().id, e);
+                        let reason = CloseReason::ConnectFailed(e);
+                        defer_shutdown(&mut g, &mut defer_free, &pd.ctx, reason);
+                        continue;
+                    }
+                }
+            }
+            if pd.who == 1 && pd.ctx.borrow().state == State::Handshake {
+                let res = pd.ctx.borrow_mut().advance_handshake();
+                match res {
+                    Ok(false) => continue,
+                    Ok(true) => {
+                        let ctx = pd.ctx.borrow();
+                        log!("conn {}: backend_fd {} tunneled", ctx.id, ctx.backend_fd);
+                        // buffered client data and backend data that came with
+                        // the last reply have no edge of their own
+                        events |= (libc::EPOLLIN | libc::EPOLLOUT) as u32;
+                    }
+                    Err(e) => {
+                        log!(
+                            "conn {}: upstream handshake failed: {}",
+                            pd.ctx.borrow().id,
+                            e
+                        );
+                        let reason = CloseReason::ConnectFailed(e);
+                        defer_shutdown(&mut g, &mut defer_free, &pd.ctx, reason);
+                        continue;
+                    }
+                }
+            }
+            // readable is a direction with this fd as source, writable one with
+            // it as destination. Both raised at once take a single pass
+            let readable = events & (libc::EPOLLIN | libc::EPOLLRDHUP | libc::EPOLLERR) as u32 != 0;
+            let writable = events & (libc::EPOLLOUT | libc::EPOLLERR | libc::EPOLLHUP) as u32 != 0;
+            let (from, to) = if pd.who == 0 {
+                (readable, writable)
+            } else {
+                (writable, readable)
+            };
+            if let Err(e) = pd.ctx.borrow_mut().copy_pass(from, to) {
+                free = Some(e);
+            }
+            if free.is_none() && pd.who == 0 {
+                let want_backend = {
+                    let ctx = pd.ctx.borrow();
+                    ctx.backend_fd < 0 && !ctx.queued && (routed || !ctx.in_buf.is_empty())
+                };
+                if want_backend {
+                    if let Err(e) = request_connect(&mut g, &pd.ctx) {
+                        log!("conn {}: connect backend failed: {}", pd.ctx.borrow().id, e);
+                        free = Some(CloseReason::ConnectFailed(e));
+                    }
+                }
+            }
+            match free {
+                Some(CloseReason::BackendReset)
+                    if retry_reset(&mut g, &pd.ctx, &mut defer_rebind) => {}
+                Some(reason) => defer_shutdown(&mut g, &mut defer_free, &pd.ctx, reason),
+                None => after_copy(&pd.ctx, &mut ready, &mut defer_rebind),
+            }
+        }
+        for _ in 0..turns {
+            let ctx = ready.pop_front().unwrap();
+            let res = {
+                let mut c = ctx.borrow_mut();
+                c.ready = false;
+                // the edges raised on RESUME give it its turn
+                if c.state == State::Closing || c.paused {
+                    continue;
+                }
+                c.copy_pass(true, true)
+            };
+            match res {
+                Ok(()) => after_copy(&ctx, &mut ready, &mut defer_rebind),
+                Err(CloseReason::BackendReset) if retry_reset(&mut g, &ctx, &mut defer_rebind) => {}
+                Err(reason) => defer_shutdown(&mut g, &mut defer_free, &ctx, reason),
+            }
+        }
+        for (v, reason) in defer_free.drain(..) {
+            close_context(&mut g, &mut v.borrow_mut(), reason);
+        }
+        // a context shows up once per event of the batch
+        for ctx in defer_rebind.drain(..) {
+            if let Err(reason) = finish_rebind(&mut g, &ctx) {
+                log!("conn {}: rebind failed: {}", ctx.borrow().id, reason);
+                if ctx.borrow().connect_in_flight() {
+                    g.connecting -= 1;
+                }
+                close_context(&mut g, &mut ctx.borrow_mut(), reason);
+            }
+        }
+        while g.can_connect() {
+            let ctx = match g.pending.pop_front() {
+                Some(ctx) => ctx,
+                None => break,
+            };
+            if ctx.borrow().state == State::Closing {
+                continue;
+            }
+            ctx.borrow_mut().queued = false;
+            if let Err(e) = connect_backend(&mut g, &ctx) {
+                log!("conn {}: connect backend failed: {}", ctx.borrow().id, e);
+                close_context(&mut g, &mut ctx.borrow_mut(), CloseReason::ConnectFailed(e));
+            }
+        }
+        if g.draining && g.conns.is_empty() && g.delayed == 0 {
+            if stopping {
+                log!("runtime: drained, exiting");
+            } else {
+                log!("upgrade: drained, exiting");
+            }
+            if control_fd.is_some() {
+                let _ = fs::remove_file(&g.config.control);
+            }
+            exit_summary(&g);
+            process::exit(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Once;
+
+    fn parse(args: &[&str]) -> Config {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn default_listen_without_listeners() {
+        let config = parse(&["-d", "127.0.0.1:80"]);
+        assert_eq!(config.listen_addrs, vec![DEFAULT_LISTEN.to_string()]);
+    }
+
+    #[test]
+    fn tls_listen_alone_has_no_plain_listener() {
+        let config = parse(&[
+            "-tls-l",
+            "127.0.0.1:8443",
+            "-tls-cert",
+            "cert.pem",
+            "-tls-key",
+            "key.pem",
+        ]);
+        assert!(config.listen_addrs.is_empty());
+        assert_eq!(config.tls_listen_addrs, vec!["127.0.0.1:8443".to_string()]);
+    }
+
+    fn max_events(s: &str) -> Option<usize> {
+        parse_max_events(Some(s.to_string()))
+    }
+
+    #[test]
+    fn max_events_within_bounds() {
+        assert_eq!(max_events("16"), Some(MIN_EVENTS));
+        assert_eq!(max_events("64"), Some(64));
+        assert_eq!(max_events("65536"), Some(MAX_EVENTS));
+    }
+
+    #[test]
+    fn max_events_out_of_bounds() {
+        assert_eq!(max_events("0"), None);
+        assert_eq!(max_events("15"), None);
+        assert_eq!(max_events("65537"), None);
+        assert_eq!(max_events("18446744073709551616"), None);
+    }
+
+    #[test]
+    fn max_events_not_a_number() {
+        for s in &["", "abc", "-1", "64k", " 64", "6.4"] {
+            assert_eq!(max_events(s), None, "{:?}", s);
+        }
+        assert_eq!(parse_max_events(None), None);
+    }
+
+    #[test]
+    fn token_round_trip() {
+        // the highest address of user space, 8 byte aligned
+        let pd = 0x7fff_ffff_fff8 as *mut PollDesp;
+        let tokens = [
+            Token::Listener(0),
+            Token::Listener(u32::MAX as usize),
+            Token::Signal,
+            Token::Timer,
+            Token::Control,
+            Token::Sink(0),
+            Token::Sink(i32::MAX),
+            Token::Health,
+            Token::HealthClient(i32::MAX),
+            Token::ControlClient(i32::MAX),
+            Token::Conn(pd),
+        ];
+        for &token in &tokens {
+            let data = u64::from(token);
+            assert_eq!(Token::from(data), token);
+            let special = !matches!(token, Token::Conn(_));
+            assert_eq!(data & TOKEN_SPECIAL != 0, special, "{:?}", token);
+        }
+        assert_eq!(u64::from(Token::Conn(pd)), pd as u64);
+    }
+
+    #[test]
+    fn tokens_are_distinct() {
+        let tokens = [
+            Token::Listener(0),
+            Token::Signal,
+            Token::Timer,
+            Token::Control,
+            Token::Sink(0),
+            Token::Health,
+            Token::HealthClient(0),
+            Token::ControlClient(0),
+        ];
+        for (i, &a) in tokens.iter().enumerate() {
+            for &b in &tokens[i + 1..] {
+                assert_ne!(u64::from(a), u64::from(b), "{:?} {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "bad epoll token")]
+    fn unknown_kind_panics() {
+        let _ = Token::from(TOKEN_SPECIAL | 9 << 32);
+    }
+
+    // the probed pipe size the default comes from, as at startup
+    fn init_sys() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| sys::init(false, None));
+    }
+
+    fn pipe_size_in_effect(buf: &IoBuf) -> isize {
+        syscall!(libc::fcntl(buf.pfd[1], libc::F_GETPIPE_SZ)).unwrap() as isize
+    }
+
+    #[test]
+    fn io_buf_default_size_is_in_effect() {
+        init_sys();
+        let buf = IoBuf::new(0, 0).unwrap();
+        assert_eq!(buf.size, pipe_size_in_effect(&buf));
+        assert_eq!(buf.chunk, buf.size);
+    }
+
+    #[test]
+    fn io_buf_size_is_in_effect() {
+        init_sys();
+        // the kernel rounds up to a power of two pages
+        let buf = IoBuf::new(100_000, 4096).unwrap();
+        assert_eq!(buf.size, pipe_size_in_effect(&buf));
+        assert!(buf.size >= 100_000);
+        assert_eq!(buf.chunk, 4096);
+    }
+
+    #[test]
+    fn json_string_escapes() {
+        assert_eq!(json_string("db:5432"), "\"db:5432\"");
+        assert_eq!(json_string("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(json_string("a\tb"), "\"a\\u0009b\"");
+    }
 }
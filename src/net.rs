@@ -0,0 +1,801 @@
+use libc;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::ptr;
+use std::time::Duration;
+use sys::SysResult;
+
+fn sa_to_raw(sa: &SocketAddrV4) -> libc::sockaddr_in {
+    let ip = sa.ip().octets();
+    libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: sa.port().to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: (ip[3] as u32) << 24
+                | (ip[2] as u32) << 16
+                | (ip[1] as u32) << 8
+                | (ip[0] as u32),
+        },
+        ..unsafe { mem::zeroed() }
+    }
+}
+
+fn sa6_to_raw(sa: &SocketAddrV6) -> libc::sockaddr_in6 {
+    let mut inaddr: libc::in6_addr = unsafe { mem::zeroed() };
+    inaddr.s6_addr = sa.ip().octets();
+    libc::sockaddr_in6 {
+        sin6_family: libc::AF_INET6 as libc::sa_family_t,
+        sin6_port: sa.port().to_be(),
+        sin6_flowinfo: sa.flowinfo(),
+        sin6_addr: inaddr,
+        sin6_scope_id: sa.scope_id(),
+    }
+}
+
+fn sa_call(
+    fd: i32,
+    addr: &SocketAddr,
+    f: unsafe extern "C" fn(i32, *const libc::sockaddr, libc::socklen_t) -> i32,
+) -> SysResult<i32> {
+    match *addr {
+        SocketAddr::V4(sa) => {
+            let sin = sa_to_raw(&sa);
+            syscall!(f(
+                fd,
+                &sin as *const _ as *const _,
+                mem::size_of_val(&sin) as libc::socklen_t
+            ))
+        }
+        SocketAddr::V6(sa) => {
+            let sin = sa6_to_raw(&sa);
+            syscall!(f(
+                fd,
+                &sin as *const _ as *const _,
+                mem::size_of_val(&sin) as libc::socklen_t
+            ))
+        }
+    }
+}
+
+// source ports for backend connects, handed out round robin
+pub struct PortRange {
+    lo: u16,
+    hi: u16,
+    next: u16,
+}
+
+impl PortRange {
+    pub fn new(lo: u16, hi: u16) -> PortRange {
+        PortRange { lo, hi, next: lo }
+    }
+
+    fn take(&mut self) -> u16 {
+        let port = self.next;
+        self.next = if port >= self.hi { self.lo } else { port + 1 };
+        port
+    }
+
+    fn count(&self) -> usize {
+        (self.hi - self.lo) as usize + 1
+    }
+}
+
+// an integer socket option of -sockopt, level:name=value with the
+// symbolic names of the C headers
+#[derive(Clone, Copy)]
+pub struct SockOpt {
+    level: i32,
+    name: i32,
+    value: i32,
+    label: &'static str,
+}
+
+// symbol and value of a level or an option
+type Symbol = (&'static str, i32);
+
+const SOCK_OPTS: &[(Symbol, &[Symbol])] = &[
+    (
+        ("SOL_SOCKET", libc::SOL_SOCKET),
+        &[
+            ("SO_KEEPALIVE", libc::SO_KEEPALIVE),
+            ("SO_RCVBUF", libc::SO_RCVBUF),
+            ("SO_SNDBUF", libc::SO_SNDBUF),
+            ("SO_RCVLOWAT", libc::SO_RCVLOWAT),
+            ("SO_PRIORITY", libc::SO_PRIORITY),
+            ("SO_MARK", libc::SO_MARK),
+        ],
+    ),
+    (
+        ("IPPROTO_TCP", libc::IPPROTO_TCP),
+        &[
+            ("TCP_NODELAY", libc::TCP_NODELAY),
+            ("TCP_CORK", libc::TCP_CORK),
+            ("TCP_QUICKACK", libc::TCP_QUICKACK),
+            ("TCP_MAXSEG", libc::TCP_MAXSEG),
+            ("TCP_WINDOW_CLAMP", libc::TCP_WINDOW_CLAMP),
+            ("TCP_KEEPIDLE", libc::TCP_KEEPIDLE),
+            ("TCP_KEEPINTVL", libc::TCP_KEEPINTVL),
+            ("TCP_KEEPCNT", libc::TCP_KEEPCNT),
+            ("TCP_USER_TIMEOUT", libc::TCP_USER_TIMEOUT),
+            ("TCP_NOTSENT_LOWAT", libc::TCP_NOTSENT_LOWAT),
+        ],
+    ),
+    (
+        ("IPPROTO_IP", libc::IPPROTO_IP),
+        &[("IP_TOS", libc::IP_TOS), ("IP_TTL", libc::IP_TTL)],
+    ),
+    (
+        ("IPPROTO_IPV6", libc::IPPROTO_IPV6),
+        &[
+            ("IPV6_TCLASS", libc::IPV6_TCLASS),
+            ("IPV6_UNICAST_HOPS", libc::IPV6_UNICAST_HOPS),
+        ],
+    ),
+];
+
+impl SockOpt {
+    pub fn parse(s: &str) -> Option<SockOpt> {
+        let (level, rest) = s.split_once(':')?;
+        let (name, value) = rest.split_once('=')?;
+        let &((_, level), names) = SOCK_OPTS.iter().find(|((n, _), _)| *n == level)?;
+        let &(label, name) = names.iter().find(|(n, _)| *n == name)?;
+        Some(SockOpt {
+            level,
+            name,
+            value: value.parse().ok()?,
+            label,
+        })
+    }
+
+    // SO_MARK, the firewall mark of the packets of a socket
+    pub fn mark(mark: u32) -> SockOpt {
+        SockOpt {
+            level: libc::SOL_SOCKET,
+            name: libc::SO_MARK,
+            value: mark as i32,
+            label: "SO_MARK",
+        }
+    }
+
+    pub fn set(&self, fd: i32) -> SysResult<()> {
+        syscall!(libc::setsockopt(
+            fd,
+            self.level,
+            self.name,
+            &self.value as *const _ as *const _,
+            mem::size_of_val(&self.value) as libc::socklen_t
+        ))
+        .map(|_| ())
+    }
+}
+
+impl fmt::Display for SockOpt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.label, self.value)
+    }
+}
+
+// whether this process may set opt, EPERM for SO_MARK without
+// CAP_NET_ADMIN
+pub fn sock_opt_allowed(opt: &SockOpt) -> SysResult<()> {
+    let fd = stream_socket(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), 0)?;
+    let r = opt.set(fd);
+    unsafe { libc::close(fd) };
+    r
+}
+
+// an option the socket does not take is logged, the connection goes on
+// without it
+pub fn set_sock_opts(fd: i32, opts: &[SockOpt]) {
+    for opt in opts {
+        if let Err(e) = opt.set(fd) {
+            log!("sockopt {} on fd {} failed: {}", opt, fd, e);
+        }
+    }
+}
+
+fn stream_socket(addr: &SocketAddr, protocol: i32) -> SysResult<i32> {
+    syscall!(libc::socket(
+        match *addr {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        },
+        libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+        protocol,
+    ))
+}
+
+// an MPTCP socket when mptcp is set and the kernel has it enabled, plain TCP
+// otherwise
+fn tcp_socket(addr: &SocketAddr, mptcp: bool) -> SysResult<i32> {
+    if mptcp {
+        match stream_socket(addr, libc::IPPROTO_MPTCP) {
+            // not built in, or net.mptcp.enabled is 0
+            Err(e) if e == libc::EPROTONOSUPPORT || e == libc::ENOPROTOOPT || e == libc::EINVAL => {
+                debug!("mptcp socket failed: {}", e)
+            }
+            r => return r,
+        }
+    }
+    stream_socket(addr, 0)
+}
+
+// whether MPTCP sockets can be created at all
+pub fn mptcp_available() -> SysResult<()> {
+    let fd = stream_socket(
+        &SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        libc::IPPROTO_MPTCP,
+    )?;
+    unsafe { libc::close(fd) };
+    Ok(())
+}
+
+// true on an MPTCP socket whose peer agreed to MPTCP, false on plain TCP or
+// after a fallback to it
+pub fn is_mptcp(fd: i32) -> SysResult<bool> {
+    let mut on: i32 = 0;
+    let mut len = mem::size_of_val(&on) as libc::socklen_t;
+    // TCP_IS_MPTCP, Linux 5.16, not exported by libc
+    syscall!(libc::getsockopt(
+        fd,
+        libc::IPPROTO_TCP,
+        43,
+        &mut on as *mut _ as *mut _,
+        &mut len
+    ))?;
+    Ok(on != 0)
+}
+
+// struct tcp_md5sig of linux/tcp.h, not in libc
+#[repr(C)]
+struct TcpMd5Sig {
+    addr: libc::sockaddr_storage,
+    flags: u8,
+    prefixlen: u8,
+    keylen: u16,
+    ifindex: i32,
+    key: [u8; libc::TCP_MD5SIG_MAXKEYLEN],
+}
+
+// RFC 2385 signatures with key on the segments to and from peer, set
+// before the SYN goes out. ENOPROTOOPT without CONFIG_TCP_MD5SIG
+fn set_md5sig(fd: i32, peer: &SocketAddr, key: &[u8]) -> SysResult<()> {
+    let mut sig: TcpMd5Sig = unsafe { mem::zeroed() };
+    if key.len() > sig.key.len() {
+        return Err(libc::EINVAL);
+    }
+    unsafe {
+        match *peer {
+            SocketAddr::V4(ref sa) => ptr::write(&mut sig.addr as *mut _ as *mut _, sa_to_raw(sa)),
+            SocketAddr::V6(ref sa) => ptr::write(&mut sig.addr as *mut _ as *mut _, sa6_to_raw(sa)),
+        }
+    }
+    sig.keylen = key.len() as u16;
+    sig.key[..key.len()].copy_from_slice(key);
+    syscall!(libc::setsockopt(
+        fd,
+        libc::IPPROTO_TCP,
+        libc::TCP_MD5SIG,
+        &sig as *const _ as *const _,
+        mem::size_of_val(&sig) as libc::socklen_t
+    ))
+    .map(|_| ())
+}
+
+// whether the kernel signs TCP segments at all
+pub fn md5sig_available() -> SysResult<()> {
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    let fd = stream_socket(&addr, 0)?;
+    let r = set_md5sig(fd, &addr, b"probe");
+    unsafe { libc::close(fd) };
+    r
+}
+
+// a local address binds the socket before connecting, port 0 leaves the
+// source port to the kernel. transparent allows an address of another host,
+// md5 signs the connection with that key
+fn connect_from(
+    addr: &SocketAddr,
+    local: Option<SocketAddr>,
+    transparent: bool,
+    mptcp: bool,
+    opts: &[SockOpt],
+    md5: Option<&[u8]>,
+) -> SysResult<i32> {
+    let fd = tcp_socket(addr, mptcp)?;
+    set_sock_opts(fd, opts);
+    if let Some(key) = md5 {
+        if let Err(e) = set_md5sig(fd, addr, key) {
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+    }
+    if let Some(local) = local {
+        let on: i32 = 1;
+        let r = if transparent {
+            set_transparent(fd, addr)
+        } else {
+            Ok(())
+        }
+        .and_then(|_| {
+            syscall!(libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_REUSEADDR,
+                &on as *const _ as *const _,
+                mem::size_of_val(&on) as libc::socklen_t
+            ))
+        })
+        .and_then(|_| sa_call(fd, &local, libc::bind));
+        if let Err(e) = r {
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+    }
+    if let Err(e) = sa_call(fd, addr, libc::connect) {
+        if e != libc::EINPROGRESS {
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+    }
+    Ok(fd)
+}
+
+// src_ip binds the socket to that address before connecting, with
+// src_ports to the first usable port of the range as well. mptcp asks for
+// Multipath TCP where the kernel offers it, opts are set before the connect
+// and md5 signs it
+pub fn connect_tcp(
+    addr: &SocketAddr,
+    src_ip: Option<IpAddr>,
+    src_ports: Option<&mut PortRange>,
+    mptcp: bool,
+    opts: &[SockOpt],
+    md5: Option<&[u8]>,
+) -> SysResult<i32> {
+    let ip = src_ip.unwrap_or(match *addr {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    });
+    let ports = match src_ports {
+        Some(ports) => ports,
+        None => {
+            let local = src_ip.map(|ip| SocketAddr::new(ip, 0));
+            return connect_from(addr, local, false, mptcp, opts, md5);
+        }
+    };
+    for _ in 0..ports.count() {
+        let port = ports.take();
+        let local = Some(SocketAddr::new(ip, port));
+        match connect_from(addr, local, false, mptcp, opts, md5) {
+            Err(e) if e == libc::EADDRINUSE || e == libc::EADDRNOTAVAIL => {
+                debug!("source port {} busy: {}", port, e)
+            }
+            r => return r,
+        }
+    }
+    log!("no source port free on {} in {}-{}", ip, ports.lo, ports.hi);
+    Err(libc::EADDRINUSE)
+}
+
+// connects from source, an address that need not be one of this host, for
+// -transparent
+pub fn connect_transparent(
+    addr: &SocketAddr,
+    source: IpAddr,
+    opts: &[SockOpt],
+    md5: Option<&[u8]>,
+) -> SysResult<i32> {
+    connect_from(
+        addr,
+        Some(SocketAddr::new(source, 0)),
+        true,
+        false,
+        opts,
+        md5,
+    )
+}
+
+// IP_TRANSPARENT, needs CAP_NET_ADMIN
+fn set_transparent(fd: i32, addr: &SocketAddr) -> SysResult<()> {
+    let (level, opt) = match *addr {
+        SocketAddr::V4(_) => (libc::SOL_IP, libc::IP_TRANSPARENT),
+        SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+    };
+    let on: i32 = 1;
+    syscall!(libc::setsockopt(
+        fd,
+        level,
+        opt,
+        &on as *const _ as *const _,
+        mem::size_of_val(&on) as libc::socklen_t
+    ))
+    .map(|_| ())
+}
+
+// one nonblocking send, for a socket that is not written otherwise
+pub fn send_some(fd: i32, data: &[u8]) -> SysResult<usize> {
+    syscall!(libc::send(
+        fd,
+        data.as_ptr() as *const _,
+        data.len(),
+        libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL
+    ))
+    .map(|n| n as usize)
+}
+
+// peeks at most one byte without consuming it
+pub fn peek(fd: i32) -> SysResult<usize> {
+    peek_into(fd, &mut [0u8])
+}
+
+// what fits of the pending data, left in the socket
+pub fn peek_into(fd: i32, buf: &mut [u8]) -> SysResult<usize> {
+    syscall!(libc::recv(
+        fd,
+        buf.as_mut_ptr() as *mut _,
+        buf.len(),
+        libc::MSG_PEEK | libc::MSG_DONTWAIT
+    ))
+    .map(|n| n as usize)
+}
+
+// drops up to len bytes pending on a TCP socket without copying them out,
+// the bytes dropped, 0 on EOF
+pub fn discard(fd: i32, len: usize) -> SysResult<usize> {
+    syscall!(libc::recv(
+        fd,
+        ptr::null_mut(),
+        len,
+        libc::MSG_TRUNC | libc::MSG_DONTWAIT
+    ))
+    .map(|n| n as usize)
+}
+
+fn tcp_info(fd: i32) -> SysResult<libc::tcp_info> {
+    let mut info: libc::tcp_info = unsafe { mem::zeroed() };
+    let mut len = mem::size_of_val(&info) as libc::socklen_t;
+    syscall!(libc::getsockopt(
+        fd,
+        libc::IPPROTO_TCP,
+        libc::TCP_INFO,
+        &mut info as *mut _ as *mut _,
+        &mut len
+    ))?;
+    Ok(info)
+}
+
+// (bytes sent, bytes retransmitted) of a TCP socket, retransmissions are
+// part of the sent bytes. Kernels before 4.19 leave both at 0
+pub fn tcp_sent(fd: i32) -> SysResult<(u64, u64)> {
+    let info = tcp_info(fd)?;
+    Ok((info.tcpi_bytes_sent, info.tcpi_bytes_retrans))
+}
+
+// bytes the peer acknowledged, it stops moving once the peer's application
+// no longer reads. Kernels before 4.1 leave it at 0
+pub fn tcp_acked(fd: i32) -> SysResult<u64> {
+    Ok(tcp_info(fd)?.tcpi_bytes_acked)
+}
+
+// true once the peer sent its FIN, whether or not data is still unread
+pub fn peer_closed(fd: i32) -> SysResult<bool> {
+    // TCP_CLOSE_WAIT, not exported by libc
+    Ok(tcp_info(fd)?.tcpi_state == 8)
+}
+
+// true once the connection is gone without a close of our own, after a RST
+// from the peer or a retransmission timeout
+pub fn peer_reset(fd: i32) -> SysResult<bool> {
+    // TCP_CLOSE, not exported by libc
+    Ok(tcp_info(fd)?.tcpi_state == 7)
+}
+
+// time since the last segment from the peer. For a connection fresh out of
+// the accept queue that is at least how long it waited there
+pub fn peer_quiet(fd: i32) -> SysResult<Duration> {
+    let info = tcp_info(fd)?;
+    let ms = info.tcpi_last_data_recv.min(info.tcpi_last_ack_recv);
+    Ok(Duration::from_millis(ms as u64))
+}
+
+// where a client redirected by an iptables REDIRECT or DNAT rule was headed,
+// from conntrack. ENOENT when its connection was not translated
+pub fn original_dst(fd: i32) -> SysResult<SocketAddr> {
+    let local = local_addr(fd)?;
+    // a dual stack listener has IPv4 clients translated by the IPv4 table
+    let level = match local {
+        SocketAddr::V6(_) => libc::SOL_IPV6,
+        SocketAddr::V4(_) => libc::SOL_IP,
+    };
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+    // IP6T_SO_ORIGINAL_DST has the same number
+    syscall!(libc::getsockopt(
+        fd,
+        level,
+        libc::SO_ORIGINAL_DST,
+        &mut addr as *mut _ as *mut _,
+        &mut len
+    ))?;
+    let dst = raw_to_sa(&addr as *const _ as *const _).ok_or(libc::EAFNOSUPPORT)?;
+    // conntrack knows connections that came here directly as well
+    if dst.ip() == local.ip() && dst.port() == local.port() {
+        return Err(libc::ENOENT);
+    }
+    Ok(dst)
+}
+
+// nonblocking accept that also returns the peer address
+pub fn accept(listen_fd: i32) -> SysResult<(i32, Option<SocketAddr>)> {
+    let mut ss: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of_val(&ss) as libc::socklen_t;
+    let fd = syscall!(libc::accept4(
+        listen_fd,
+        &mut ss as *mut _ as *mut _,
+        &mut len,
+        libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC
+    ))?;
+    Ok((fd, raw_to_sa(&ss as *const _ as *const _).map(canonical)))
+}
+
+pub fn socket_error(fd: i32) -> SysResult<i32> {
+    let mut err: i32 = 0;
+    let mut len = mem::size_of_val(&err) as libc::socklen_t;
+    syscall!(libc::getsockopt(
+        fd,
+        libc::SOL_SOCKET,
+        libc::SO_ERROR,
+        &mut err as *mut _ as *mut _,
+        &mut len
+    ))?;
+    Ok(err)
+}
+
+// false while a nonblocking connect is still in progress
+pub fn is_connected(fd: i32) -> SysResult<bool> {
+    match peer_addr(fd) {
+        Ok(_) => Ok(true),
+        Err(e) if e == libc::ENOTCONN => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// the address of the other end, ENOTCONN until a connect has finished
+pub fn peer_addr(fd: i32) -> SysResult<SocketAddr> {
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+    syscall!(libc::getpeername(
+        fd,
+        &mut addr as *mut _ as *mut _,
+        &mut len
+    ))?;
+    raw_to_sa(&addr as *const _ as *const _)
+        .map(canonical)
+        .ok_or(libc::EAFNOSUPPORT)
+}
+
+// the address the socket is bound to, for a connecting socket the source
+// address the kernel picked
+pub fn local_addr(fd: i32) -> SysResult<SocketAddr> {
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+    syscall!(libc::getsockname(
+        fd,
+        &mut addr as *mut _ as *mut _,
+        &mut len
+    ))?;
+    raw_to_sa(&addr as *const _ as *const _)
+        .map(canonical)
+        .ok_or(libc::EAFNOSUPPORT)
+}
+
+fn sock_opt(fd: i32, opt: i32) -> SysResult<i32> {
+    let mut value: i32 = 0;
+    let mut len = mem::size_of_val(&value) as libc::socklen_t;
+    syscall!(libc::getsockopt(
+        fd,
+        libc::SOL_SOCKET,
+        opt,
+        &mut value as *mut _ as *mut _,
+        &mut len
+    ))?;
+    Ok(value)
+}
+
+// the address of fd if it is a listening TCP socket: ENOTSOCK for any
+// other kind of fd, EINVAL for a socket that is not listening or not TCP
+pub fn listening_addr(fd: i32) -> SysResult<SocketAddr> {
+    let mut st: libc::stat = unsafe { mem::zeroed() };
+    syscall!(libc::fstat(fd, &mut st))?;
+    if st.st_mode & libc::S_IFMT != libc::S_IFSOCK {
+        return Err(libc::ENOTSOCK);
+    }
+    if sock_opt(fd, libc::SO_TYPE)? != libc::SOCK_STREAM || sock_opt(fd, libc::SO_ACCEPTCONN)? == 0
+    {
+        return Err(libc::EINVAL);
+    }
+    // a unix socket has no address of the kind
+    local_addr(fd).map_err(|_| libc::EINVAL)
+}
+
+// device restricts the listener to the connections coming in on that
+// interface, transparent lets it take connections for any address
+pub fn listen_tcp(addr: &SocketAddr, device: Option<&str>, transparent: bool) -> SysResult<i32> {
+    let fd = syscall!(libc::socket(
+        match *addr {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        },
+        libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+        0,
+    ))?;
+    if let Some(device) = device {
+        let r = syscall!(libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            device.as_ptr() as *const _,
+            device.len() as libc::socklen_t
+        ));
+        if let Err(e) = r {
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+    }
+    let r = if transparent {
+        set_transparent(fd, addr)
+    } else {
+        Ok(())
+    }
+    .and_then(|_| sa_call(fd, addr, libc::bind));
+    if let Err(e) = r {
+        unsafe { libc::close(fd) };
+        return Err(e);
+    }
+    let r = syscall!(libc::listen(fd, libc::SOMAXCONN));
+    if let Err(e) = r {
+        unsafe { libc::close(fd) };
+        Err(e)
+    } else {
+        Ok(fd)
+    }
+}
+
+// an IPv4-mapped IPv6 address (::ffff:1.2.3.4), as a dual stack socket
+// has for an IPv4 peer, in its IPv4 form. The addresses of connected
+// sockets come out this way, for logs and comparisons alike
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+pub fn raw_to_sa(sa: *const libc::sockaddr) -> Option<SocketAddr> {
+    match unsafe { (*sa).sa_family } as i32 {
+        libc::AF_INET => {
+            let sin = unsafe { &*(sa as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)),
+                u16::from_be(sin.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(sa as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+// "host:port", "[v6]:port" or ":port", an empty host means any address
+pub fn split_host_port(addr: &str) -> Result<(&str, &str), String> {
+    let i = match addr.rfind(':') {
+        Some(i) => i,
+        None => return Err(format!("missing port in address {}", addr)),
+    };
+    let (host, port) = (&addr[..i], &addr[i + 1..]);
+    let host = if host.starts_with('[') && host.ends_with(']') {
+        &host[1..host.len() - 1]
+    } else if host.contains(':') {
+        return Err(format!("too many colons in address {}", addr));
+    } else {
+        host
+    };
+    if port.is_empty() {
+        return Err(format!("missing port in address {}", addr));
+    }
+    Ok((host, port))
+}
+
+pub fn resolve_address(addr: &str, passive: bool) -> Result<Vec<SocketAddr>, String> {
+    resolve_address_flags(addr, if passive { libc::AI_PASSIVE } else { 0 })
+}
+
+// getaddrinfo with the ai_flags the caller passes, which may be
+// AI_ADDRCONFIG to skip the families the host has no address of or
+// AI_NUMERICHOST to keep off the resolver
+pub fn resolve_address_flags(addr: &str, ai_flags: i32) -> Result<Vec<SocketAddr>, String> {
+    let (host, port) = split_host_port(addr)?;
+    let host = if host.is_empty() {
+        None
+    } else {
+        Some(CString::new(host).map_err(|_| format!("invalid host in address {}", addr))?)
+    };
+    let port = CString::new(port).map_err(|_| format!("invalid port in address {}", addr))?;
+    let mut hints: libc::addrinfo = unsafe { mem::zeroed() };
+    hints.ai_family = libc::AF_UNSPEC;
+    hints.ai_socktype = libc::SOCK_STREAM;
+    hints.ai_flags = ai_flags;
+    let mut res: *mut libc::addrinfo = ptr::null_mut();
+    let r = unsafe {
+        libc::getaddrinfo(
+            host.as_ref().map_or(ptr::null(), |h| h.as_ptr()),
+            port.as_ptr(),
+            &hints,
+            &mut res,
+        )
+    };
+    if r != 0 {
+        let msg = unsafe { CStr::from_ptr(libc::gai_strerror(r)) };
+        return Err(format!("resolve {}: {}", addr, msg.to_string_lossy()));
+    }
+    let mut addrs = Vec::new();
+    let mut ai = res;
+    while !ai.is_null() {
+        let info = unsafe { &*ai };
+        if let Some(sa) = raw_to_sa(info.ai_addr) {
+            addrs.push(sa);
+        }
+        ai = info.ai_next;
+    }
+    unsafe { libc::freeaddrinfo(res) };
+    if addrs.is_empty() {
+        return Err(format!("resolve {}: no address", addr));
+    }
+    Ok(addrs)
+}
+
+pub fn resolve_first(addr: &str, passive: bool) -> Result<SocketAddr, String> {
+    resolve_address(addr, passive).map(|addrs| addrs[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn mapped_v4_becomes_v4() {
+        assert_eq!(canonical(addr("[::ffff:1.2.3.4]:80")), addr("1.2.3.4:80"));
+        assert_eq!(
+            canonical(addr("[::ffff:127.0.0.1]:65535")),
+            addr("127.0.0.1:65535")
+        );
+    }
+
+    #[test]
+    fn native_addresses_stay() {
+        for s in &["[2001:db8::1]:443", "[::1]:8080", "[::]:0", "10.0.0.1:1"] {
+            assert_eq!(canonical(addr(s)), addr(s));
+        }
+        // IPv4-compatible addresses are IPv6 ones
+        assert_eq!(canonical(addr("[::1.2.3.4]:80")), addr("[::1.2.3.4]:80"));
+    }
+
+    #[test]
+    fn ports_are_kept() {
+        for port in [0, 1, 80, 65535] {
+            let mapped = SocketAddr::new("::ffff:192.0.2.7".parse().unwrap(), port);
+            assert_eq!(canonical(mapped).port(), port);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::time::{Duration, Instant};
+
+const BUCKETS: usize = 32;
+
+// fixed power of two buckets in microseconds, bucket i counts the samples
+// below 2^i us, the last one everything above
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    sum_us: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            buckets: [0; BUCKETS],
+            count: 0,
+            sum_us: 0,
+        }
+    }
+
+    pub fn record(&mut self, d: Duration) {
+        let us = d.as_micros() as u64;
+        let i = (64 - us.leading_zeros()) as usize;
+        self.buckets[i.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.sum_us += us;
+    }
+
+    pub fn dump(&self, name: &str) {
+        if self.count == 0 {
+            log!("{}: no samples", name);
+            return;
+        }
+        log!(
+            "{}: {} samples, mean {}us",
+            name,
+            self.count,
+            self.sum_us / self.count
+        );
+        for (i, &n) in self.buckets.iter().enumerate() {
+            if n == 0 {
+                continue;
+            }
+            if i == BUCKETS - 1 {
+                log!("  >= {}us {}", 1u64 << (i - 1), n);
+            } else {
+                log!("  < {}us {}", 1u64 << i, n);
+            }
+        }
+    }
+}
+
+// accepts per second, counted in windows of a whole second. The rate is
+// that of the last window, or of the current one once it got ahead
+pub struct AcceptRate {
+    start: Instant,
+    current: u64,
+    last: u64,
+}
+
+impl AcceptRate {
+    pub fn new(now: Instant) -> AcceptRate {
+        AcceptRate {
+            start: now,
+            current: 0,
+            last: 0,
+        }
+    }
+
+    pub fn add(&mut self, now: Instant) {
+        self.roll(now);
+        self.current += 1;
+    }
+
+    pub fn rate(&mut self, now: Instant) -> u64 {
+        self.roll(now);
+        self.last.max(self.current)
+    }
+
+    fn roll(&mut self, now: Instant) {
+        let secs = now.saturating_duration_since(self.start).as_secs();
+        if secs == 0 {
+            return;
+        }
+        // a quiet second in between leaves nothing of the last window
+        self.last = if secs == 1 { self.current } else { 0 };
+        self.current = 0;
+        self.start += Duration::from_secs(secs);
+    }
+}
+
+// counters of one backend, active are the connections holding a socket to
+// it and the bytes are those moved while they did
+#[derive(Clone, Default)]
+pub struct BackendStats {
+    pub connects: u64,
+    pub active: u64,
+    pub connect_failures: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    // the last connect to it failed
+    pub down: bool,
+}
+
+// TCP_INFO totals of the two sockets of a connection, in is what went out to
+// the backend and out what went out to the client
+#[derive(Clone, Copy, Default)]
+pub struct Wire {
+    pub sent_in: u64,
+    pub retrans_in: u64,
+    pub sent_out: u64,
+    pub retrans_out: u64,
+}
+
+// application bytes moved per direction, next to the wire bytes when the
+// sockets were sampled
+#[derive(Clone, Default)]
+pub struct Traffic {
+    bytes_in: u64,
+    bytes_out: u64,
+    wire: Option<Wire>,
+}
+
+impl Traffic {
+    pub fn add(&mut self, bytes_in: u64, bytes_out: u64, wire: Option<Wire>) {
+        self.bytes_in += bytes_in;
+        self.bytes_out += bytes_out;
+        if let Some(w) = wire {
+            let total = self.wire.get_or_insert_with(Wire::default);
+            total.sent_in += w.sent_in;
+            total.retrans_in += w.retrans_in;
+            total.sent_out += w.sent_out;
+            total.retrans_out += w.retrans_out;
+        }
+    }
+
+    pub fn dump(&self) {
+        log!("bytes in {} out {}", self.bytes_in, self.bytes_out);
+        if let Some(ref w) = self.wire {
+            log!(
+                "wire in {} (retrans {}) out {} (retrans {})",
+                w.sent_in,
+                w.retrans_in,
+                w.sent_out,
+                w.retrans_out
+            );
+        }
+    }
+}
+
+// copies that stopped on a full pipe the destination took nothing more
+// from, and the connections they happened on
+#[derive(Clone, Copy, Default)]
+pub struct PipeFull {
+    times: u64,
+    conns: u64,
+}
+
+impl PipeFull {
+    pub fn add(&mut self, times: u64) {
+        if times > 0 {
+            self.times += times;
+            self.conns += 1;
+        }
+    }
+
+    pub fn dump(&self, name: &str) {
+        log!(
+            "pipe full {}: {} times on {} connections",
+            name,
+            self.times,
+            self.conns
+        );
+    }
+}
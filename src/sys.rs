@@ -0,0 +1,359 @@
+use libc;
+use std::env;
+use std::ffi::CString;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+use std::time::Duration;
+
+pub type SysResult<T> = Result<T, i32>;
+
+macro_rules! syscall {
+    ($e: expr) => {{
+        let r = unsafe { $e };
+        if r < 0 {
+            Err(unsafe { *::libc::__errno_location() })
+        } else {
+            Ok(r)
+        }
+    }};
+}
+
+static mut VERBOSE: bool = false;
+
+pub fn verbose() -> bool {
+    unsafe { VERBOSE }
+}
+
+// stdout unless -syslog picked syslog
+static mut SYSLOG: bool = false;
+
+macro_rules! log {
+    ($($arg: tt)*) => {
+        $crate::sys::log(::libc::LOG_INFO, &format!($($arg)*))
+    };
+}
+
+macro_rules! debug {
+    ($($arg: tt)*) => {
+        if $crate::sys::verbose() {
+            $crate::sys::log(::libc::LOG_DEBUG, &format!($($arg)*));
+        }
+    };
+}
+
+pub fn log(priority: i32, msg: &str) {
+    if !unsafe { SYSLOG } {
+        println!("{}", msg);
+        return;
+    }
+    // a NUL in the message would cut it short
+    if let Ok(msg) = CString::new(msg.replace('\0', "")) {
+        unsafe { libc::syslog(priority, b"%s\0".as_ptr() as *const _, msg.as_ptr()) };
+    }
+}
+
+pub fn syslog_facility(name: &str) -> Option<i32> {
+    let facility = match name {
+        "daemon" => libc::LOG_DAEMON,
+        "user" => libc::LOG_USER,
+        "local0" => libc::LOG_LOCAL0,
+        "local1" => libc::LOG_LOCAL1,
+        "local2" => libc::LOG_LOCAL2,
+        "local3" => libc::LOG_LOCAL3,
+        "local4" => libc::LOG_LOCAL4,
+        "local5" => libc::LOG_LOCAL5,
+        "local6" => libc::LOG_LOCAL6,
+        "local7" => libc::LOG_LOCAL7,
+        _ => return None,
+    };
+    Some(facility)
+}
+
+static mut EPOLL_FD: i32 = 0;
+
+pub fn epoll_fd() -> i32 {
+    unsafe { EPOLL_FD }
+}
+
+fn epoll_ctl(op: i32, fd: i32, rw: i32, data: u64) -> SysResult<i32> {
+    let mut events = libc::EPOLLET;
+    if rw & 1 != 0 {
+        events |= libc::EPOLLIN;
+    }
+    if rw & 2 != 0 {
+        events |= libc::EPOLLOUT;
+    }
+    syscall!(libc::epoll_ctl(
+        epoll_fd(),
+        op,
+        fd,
+        &libc::epoll_event {
+            events: events as u32,
+            u64: data
+        } as *const _ as *mut _,
+    ))
+}
+
+pub fn epoll_add(fd: i32, rw: i32, data: u64) -> SysResult<i32> {
+    epoll_ctl(libc::EPOLL_CTL_ADD, fd, rw, data)
+}
+
+pub fn epoll_mod(fd: i32, rw: i32, data: u64) -> SysResult<i32> {
+    epoll_ctl(libc::EPOLL_CTL_MOD, fd, rw, data)
+}
+
+pub fn epoll_del(fd: i32) -> SysResult<i32> {
+    syscall!(libc::epoll_ctl(
+        epoll_fd(),
+        libc::EPOLL_CTL_DEL,
+        fd,
+        ptr::null_mut(),
+    ))
+}
+
+// blocks the signals and returns a nonblocking signalfd delivering them
+pub fn signal_fd(signals: &[i32]) -> SysResult<i32> {
+    let mut mask: libc::sigset_t = unsafe { mem::zeroed() };
+    unsafe { libc::sigemptyset(&mut mask) };
+    for &sig in signals {
+        unsafe { libc::sigaddset(&mut mask, sig) };
+    }
+    syscall!(libc::sigprocmask(libc::SIG_BLOCK, &mask, ptr::null_mut()))?;
+    syscall!(libc::signalfd(
+        -1,
+        &mask,
+        libc::SFD_NONBLOCK | libc::SFD_CLOEXEC
+    ))
+}
+
+// the next pending signal of a signalfd and the pid that sent it, None once
+// drained
+pub fn read_signal(fd: i32) -> SysResult<Option<(i32, i32)>> {
+    let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+    match syscall!(libc::read(
+        fd,
+        &mut info as *mut _ as *mut _,
+        mem::size_of_val(&info)
+    )) {
+        Ok(_) => Ok(Some((info.ssi_signo as i32, info.ssi_pid as i32))),
+        Err(e) if e == libc::EAGAIN => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// how a child ended, None while it still runs
+pub fn reap(pid: i32) -> SysResult<Option<String>> {
+    let mut status = 0;
+    if syscall!(libc::waitpid(pid, &mut status, libc::WNOHANG))? == 0 {
+        return Ok(None);
+    }
+    if libc::WIFSIGNALED(status) {
+        Ok(Some(format!("killed by signal {}", libc::WTERMSIG(status))))
+    } else {
+        Ok(Some(format!(
+            "exited with status {}",
+            libc::WEXITSTATUS(status)
+        )))
+    }
+}
+
+pub fn set_cloexec(fd: i32) -> SysResult<i32> {
+    syscall!(libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC))
+}
+
+pub fn set_nonblock(fd: i32) -> SysResult<i32> {
+    let flags = syscall!(libc::fcntl(fd, libc::F_GETFL))?;
+    syscall!(libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK))
+}
+
+// forks and execs exe with the arguments of this process and vars added to
+// its environment. The fds in inherit are kept open across the exec, the
+// signals blocked for the signalfd are unblocked again
+pub fn spawn_self(exe: &Path, inherit: &[i32], vars: &[(&str, String)]) -> SysResult<i32> {
+    // everything the child needs is allocated before the fork
+    let cstr = |b: &[u8]| CString::new(b).map_err(|_| libc::EINVAL);
+    let path = cstr(exe.as_os_str().as_bytes())?;
+    let args = env::args_os()
+        .map(|arg| cstr(arg.as_bytes()))
+        .collect::<SysResult<Vec<_>>>()?;
+    let mut pairs = env::vars_os()
+        .filter(|(name, _)| {
+            !vars
+                .iter()
+                .any(|(var, _)| name.as_bytes() == var.as_bytes())
+        })
+        .map(|(name, val)| {
+            let mut pair = name.as_bytes().to_vec();
+            pair.push(b'=');
+            pair.extend_from_slice(val.as_bytes());
+            cstr(&pair)
+        })
+        .collect::<SysResult<Vec<_>>>()?;
+    for (var, value) in vars {
+        pairs.push(cstr(format!("{}={}", var, value).as_bytes())?);
+    }
+    let mut argv: Vec<_> = args.iter().map(|arg| arg.as_ptr()).collect();
+    argv.push(ptr::null());
+    let mut envp: Vec<_> = pairs.iter().map(|pair| pair.as_ptr()).collect();
+    envp.push(ptr::null());
+    let mut mask: libc::sigset_t = unsafe { mem::zeroed() };
+    unsafe { libc::sigemptyset(&mut mask) };
+
+    let pid = syscall!(libc::fork())?;
+    if pid == 0 {
+        unsafe {
+            for &fd in inherit {
+                libc::fcntl(fd, libc::F_SETFD, 0);
+            }
+            libc::sigprocmask(libc::SIG_SETMASK, &mask, ptr::null_mut());
+            libc::execve(path.as_ptr(), argv.as_ptr(), envp.as_ptr());
+            libc::_exit(127);
+        }
+    }
+    Ok(pid)
+}
+
+// a nonblocking timerfd left disarmed, see arm_timer
+pub fn oneshot_timer_fd() -> SysResult<i32> {
+    syscall!(libc::timerfd_create(
+        libc::CLOCK_MONOTONIC,
+        libc::TFD_NONBLOCK | libc::TFD_CLOEXEC
+    ))
+}
+
+// expires the timer once after the given time, replacing what was armed
+pub fn arm_timer(fd: i32, after: Duration) -> SysResult<()> {
+    // a zero value would disarm it
+    let after = after.max(Duration::new(0, 1));
+    let spec = libc::itimerspec {
+        it_interval: libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        },
+        it_value: libc::timespec {
+            tv_sec: after.as_secs() as libc::time_t,
+            tv_nsec: after.subsec_nanos() as libc::c_long,
+        },
+    };
+    syscall!(libc::timerfd_settime(fd, 0, &spec, ptr::null_mut())).map(|_| ())
+}
+
+// expirations since the last read, 0 when there were none
+pub fn read_timer(fd: i32) -> SysResult<u64> {
+    let mut n = 0u64;
+    match syscall!(libc::read(
+        fd,
+        &mut n as *mut _ as *mut _,
+        mem::size_of_val(&n)
+    )) {
+        Ok(_) => Ok(n),
+        Err(e) if e == libc::EAGAIN => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+// (soft, hard) limit of open fds
+pub fn nofile() -> SysResult<(u64, u64)> {
+    let mut limit: libc::rlimit = unsafe { mem::zeroed() };
+    syscall!(libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit))?;
+    Ok((limit.rlim_cur as u64, limit.rlim_max as u64))
+}
+
+// sets the soft limit of open fds, at most the hard limit
+pub fn set_nofile(soft: u64) -> SysResult<u64> {
+    let (_, hard) = nofile()?;
+    let limit = libc::rlimit {
+        rlim_cur: soft.min(hard) as libc::rlim_t,
+        rlim_max: hard as libc::rlim_t,
+    };
+    syscall!(libc::setrlimit(libc::RLIMIT_NOFILE, &limit))?;
+    Ok(soft.min(hard))
+}
+
+// the Linux default, for kernels or sandboxes without F_GETPIPE_SZ
+const DEFAULT_PIPE_SIZE: isize = 65536;
+
+static mut PIPE_SIZE: isize = 0;
+
+// what F_GETPIPE_SZ reported, DEFAULT_PIPE_SIZE when it failed. A size of
+// 0 would have nothing ever spliced
+fn pipe_size_or_default(reported: SysResult<i32>) -> isize {
+    match reported {
+        Ok(n) if n > 0 => n as isize,
+        Ok(n) => {
+            log!(
+                "F_GETPIPE_SZ reported {}, assuming a pipe size of {}",
+                n,
+                DEFAULT_PIPE_SIZE
+            );
+            DEFAULT_PIPE_SIZE
+        }
+        Err(e) => {
+            log!(
+                "F_GETPIPE_SZ failed: {}, assuming a pipe size of {}",
+                e,
+                DEFAULT_PIPE_SIZE
+            );
+            DEFAULT_PIPE_SIZE
+        }
+    }
+}
+
+pub fn pipe_size() -> isize {
+    unsafe { PIPE_SIZE }
+}
+
+pub fn init(verbose: bool, syslog_facility: Option<i32>) {
+    unsafe { VERBOSE = verbose };
+
+    if let Some(facility) = syslog_facility {
+        unsafe {
+            libc::openlog(
+                b"tcpproxy\0".as_ptr() as *const _,
+                libc::LOG_PID | libc::LOG_NDELAY,
+                facility,
+            );
+            SYSLOG = true;
+        }
+    }
+
+    {
+        let mut pfd = [0; 2];
+        syscall!(libc::pipe(pfd.as_mut_ptr())).unwrap();
+        let size = pipe_size_or_default(syscall!(libc::fcntl(pfd[0], libc::F_GETPIPE_SZ)));
+        unsafe { PIPE_SIZE = size };
+        unsafe {
+            libc::close(pfd[0]);
+            libc::close(pfd[1]);
+        }
+
+        log!("pipe size: {}", pipe_size());
+    }
+
+    syscall!(libc::epoll_create1(libc::EPOLL_CLOEXEC))
+        .map(|fd| unsafe {
+            EPOLL_FD = fd;
+        })
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reported_pipe_size_is_taken() {
+        assert_eq!(pipe_size_or_default(Ok(4096)), 4096);
+        assert_eq!(pipe_size_or_default(Ok(1 << 20)), 1 << 20);
+    }
+
+    #[test]
+    fn pipe_size_falls_back() {
+        assert_eq!(pipe_size_or_default(Ok(0)), DEFAULT_PIPE_SIZE);
+        assert_eq!(pipe_size_or_default(Ok(-1)), DEFAULT_PIPE_SIZE);
+        assert_eq!(pipe_size_or_default(Err(libc::EINVAL)), DEFAULT_PIPE_SIZE);
+        assert_eq!(DEFAULT_PIPE_SIZE, 64 * 1024);
+    }
+}
@@ -0,0 +1,266 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::mem;
+use std::time::{Duration, Instant};
+use sys;
+use sys::SysResult;
+
+struct Entry<T> {
+    deadline: Instant,
+    // keeps timers of the same deadline in the order they were added
+    seq: u64,
+    token: T,
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Entry<T>) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Entry<T>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Entry<T> {
+    fn cmp(&self, other: &Entry<T>) -> Ordering {
+        (self.deadline, self.seq).cmp(&(other.deadline, other.seq))
+    }
+}
+
+// the deadlines in order, earliest first
+struct Queue<T> {
+    heap: BinaryHeap<Reverse<Entry<T>>>,
+    seq: u64,
+}
+
+impl<T> Queue<T> {
+    fn new() -> Queue<T> {
+        Queue {
+            heap: BinaryHeap::new(),
+            seq: 0,
+        }
+    }
+
+    fn push(&mut self, deadline: Instant, token: T) -> u64 {
+        self.seq += 1;
+        self.heap.push(Reverse(Entry {
+            deadline,
+            seq: self.seq,
+            token,
+        }));
+        self.seq
+    }
+
+    fn remove(&mut self, seq: u64) {
+        self.heap.retain(|Reverse(entry)| entry.seq != seq);
+    }
+
+    // the deadline to arm the timerfd for
+    fn next(&self) -> Option<Instant> {
+        self.heap.peek().map(|Reverse(entry)| entry.deadline)
+    }
+
+    fn pop_due(&mut self, now: Instant) -> Vec<(Instant, T)> {
+        let mut due = Vec::new();
+        while self.next().is_some_and(|deadline| deadline <= now) {
+            let Reverse(entry) = self.heap.pop().unwrap();
+            due.push((entry.deadline, entry.token));
+        }
+        due
+    }
+
+    // takes out the timers whose token matches, they never come due
+    fn cancel<F: FnMut(&T) -> bool>(&mut self, mut matches: F) -> Vec<T> {
+        let (gone, kept): (Vec<_>, Vec<_>) = mem::take(&mut self.heap)
+            .into_vec()
+            .into_iter()
+            .partition(|Reverse(entry)| matches(&entry.token));
+        self.heap = BinaryHeap::from(kept);
+        gone.into_iter().map(|Reverse(entry)| entry.token).collect()
+    }
+}
+
+// every deadline of the event loop on a single timerfd, armed for the
+// earliest one. Timers that are due together come out of one wakeup
+pub struct Timers<T> {
+    fd: i32,
+    queue: Queue<T>,
+    // what the timerfd is armed for, None once it fired
+    armed: Option<Instant>,
+}
+
+impl<T> Timers<T> {
+    pub fn new() -> SysResult<Timers<T>> {
+        Ok(Timers {
+            fd: sys::oneshot_timer_fd()?,
+            queue: Queue::new(),
+            armed: None,
+        })
+    }
+
+    pub fn fd(&self) -> i32 {
+        self.fd
+    }
+
+    // a timer the timerfd could not be armed for is not kept, it would
+    // never fire
+    pub fn add(&mut self, deadline: Instant, token: T) -> SysResult<()> {
+        let seq = self.queue.push(deadline, token);
+        self.arm().inspect_err(|_| self.queue.remove(seq))
+    }
+
+    // the timers that are due, earliest first, with their deadlines. The
+    // timerfd is armed again for the next one; failing that is logged, the
+    // next add tries again
+    pub fn expired(&mut self, now: Instant) -> Vec<(Instant, T)> {
+        if let Err(e) = sys::read_timer(self.fd) {
+            log!("timer read failed: {}", e);
+        }
+        self.armed = None;
+        let due = self.queue.pop_due(now);
+        if let Err(e) = self.arm() {
+            log!("timer arm failed: {}", e);
+        }
+        due
+    }
+
+    // the tokens of the timers taken out. The timerfd may still fire for
+    // one of them, with nothing due then
+    pub fn cancel<F: FnMut(&T) -> bool>(&mut self, matches: F) -> Vec<T> {
+        self.queue.cancel(matches)
+    }
+
+    fn arm(&mut self) -> SysResult<()> {
+        let next = match self.queue.next() {
+            Some(next) => next,
+            None => return Ok(()),
+        };
+        if self.armed.is_some_and(|armed| armed <= next) {
+            return Ok(());
+        }
+        let after = next.saturating_duration_since(Instant::now());
+        sys::arm_timer(self.fd, after)?;
+        self.armed = Some(next);
+        Ok(())
+    }
+}
+
+// the deadline after last of a timer repeating every interval, on the
+// schedule it started on. Periods that already went by are skipped rather
+// than fired in a burst
+pub fn next_period(last: Instant, interval: Duration, now: Instant) -> Instant {
+    let mut next = last + interval;
+    if next <= now {
+        let behind = now.duration_since(next).as_nanos() / interval.as_nanos();
+        next += interval * (behind as u32 + 1);
+    }
+    next
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    #[test]
+    fn pops_in_deadline_order() {
+        let start = Instant::now();
+        let mut queue = Queue::new();
+        for &(at, token) in &[(3, 'c'), (1, 'a'), (4, 'd'), (2, 'b')] {
+            queue.push(start + secs(at), token);
+        }
+        let due = queue.pop_due(start + secs(3));
+        let tokens: Vec<_> = due.iter().map(|&(_, token)| token).collect();
+        assert_eq!(tokens, ['a', 'b', 'c']);
+        assert_eq!(due[0].0, start + secs(1));
+        assert_eq!(queue.pop_due(start + secs(3)), []);
+        assert_eq!(queue.pop_due(start + secs(4)), [(start + secs(4), 'd')]);
+    }
+
+    #[test]
+    fn equal_deadlines_keep_insertion_order() {
+        let at = Instant::now() + secs(1);
+        let mut queue = Queue::new();
+        for token in 0..8 {
+            queue.push(at, token);
+        }
+        let tokens: Vec<_> = queue.pop_due(at).into_iter().map(|(_, t)| t).collect();
+        assert_eq!(tokens, (0..8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn cancelled_entries_never_come_due() {
+        let start = Instant::now();
+        let mut queue = Queue::new();
+        for at in 1..=4 {
+            queue.push(start + secs(at), at);
+        }
+        assert_eq!(queue.cancel(|&at| at % 2 == 0), [2, 4]);
+        let tokens: Vec<_> = queue
+            .pop_due(start + secs(5))
+            .into_iter()
+            .map(|(_, t)| t)
+            .collect();
+        assert_eq!(tokens, [1, 3]);
+        assert!(queue.cancel(|_| true).is_empty());
+    }
+
+    #[test]
+    fn removed_entry_leaves_its_twin() {
+        let at = Instant::now() + secs(1);
+        let mut queue = Queue::new();
+        queue.push(at, 'a');
+        let seq = queue.push(at, 'a');
+        queue.push(at, 'b');
+        queue.remove(seq);
+        let tokens: Vec<_> = queue.pop_due(at).into_iter().map(|(_, t)| t).collect();
+        assert_eq!(tokens, ['a', 'b']);
+    }
+
+    #[test]
+    fn next_is_the_earliest_left() {
+        let start = Instant::now();
+        let mut queue = Queue::new();
+        assert_eq!(queue.next(), None);
+        queue.push(start + secs(5), 'b');
+        queue.push(start + secs(2), 'a');
+        assert_eq!(queue.next(), Some(start + secs(2)));
+        queue.pop_due(start + secs(2));
+        assert_eq!(queue.next(), Some(start + secs(5)));
+        queue.cancel(|_| true);
+        assert_eq!(queue.next(), None);
+    }
+
+    #[test]
+    fn next_period_keeps_the_schedule() {
+        let start = Instant::now();
+        let interval = secs(10);
+        assert_eq!(
+            next_period(start, interval, start + secs(3)),
+            start + secs(10)
+        );
+        // late by less than a period
+        assert_eq!(
+            next_period(start, interval, start + secs(12)),
+            start + secs(20)
+        );
+        // several periods behind, skipped rather than fired in a burst
+        assert_eq!(
+            next_period(start, interval, start + secs(35)),
+            start + secs(40)
+        );
+        // exactly on the next deadline, that one went by
+        assert_eq!(
+            next_period(start, interval, start + secs(10)),
+            start + secs(20)
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use libc;
+use rustls;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use std::convert::TryFrom;
+use std::io::{self, BufRead, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use sys::SysResult;
+use IoBuf;
+
+struct Fd(i32);
+
+impl Read for Fd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        syscall!(libc::read(self.0, buf.as_mut_ptr() as *mut _, buf.len()))
+            .map(|n| n as usize)
+            .map_err(io::Error::from_raw_os_error)
+    }
+}
+
+impl Write for Fd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        syscall!(libc::write(self.0, buf.as_ptr() as *const _, buf.len()))
+            .map(|n| n as usize)
+            .map_err(io::Error::from_raw_os_error)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn errno(e: &io::Error) -> i32 {
+    e.raw_os_error().unwrap_or(libc::EIO)
+}
+
+pub fn load_server_config(cert: &str, key: &str) -> Result<Arc<rustls::ServerConfig>, String> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("load {}: {}", cert, e))?;
+    let key_der = PrivateKeyDer::from_pem_file(key).map_err(|e| format!("load {}: {}", key, e))?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| {
+            builder
+                .with_no_client_auth()
+                .with_single_cert(certs, key_der)
+        })
+        .map(Arc::new)
+        .map_err(|e| format!("tls config: {}", e))
+}
+
+// CA bundles of the common distributions, used when no -backend-ca is given
+const SYSTEM_CA_FILES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+];
+
+// -backend-insecure: any certificate is accepted, signatures are still checked
+#[derive(Debug)]
+struct NoVerify(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerify {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer,
+        _intermediates: &[CertificateDer],
+        _server_name: &ServerName,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// ca empty means the system bundle
+pub fn load_client_config(ca: &str, insecure: bool) -> Result<Arc<rustls::ClientConfig>, String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("tls config: {}", e))?;
+    if insecure {
+        let config = builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerify(provider)))
+            .with_no_client_auth();
+        return Ok(Arc::new(config));
+    }
+    let ca = if ca.is_empty() {
+        match SYSTEM_CA_FILES.iter().find(|path| Path::new(path).exists()) {
+            Some(path) => *path,
+            None => return Err("no system CA bundle found, use -backend-ca".to_string()),
+        }
+    } else {
+        ca
+    };
+    let mut roots = rustls::RootCertStore::empty();
+    let certs = CertificateDer::pem_file_iter(ca)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("load {}: {}", ca, e))?;
+    let (added, _) = roots.add_parsable_certificates(certs);
+    if added == 0 {
+        return Err(format!("load {}: no usable CA certificates", ca));
+    }
+    Ok(Arc::new(
+        builder.with_root_certificates(roots).with_no_client_auth(),
+    ))
+}
+
+pub fn server_name(name: &str) -> Result<ServerName<'static>, String> {
+    ServerName::try_from(name.to_string()).map_err(|e| format!("server name {}: {}", name, e))
+}
+
+// one TLS side of a connection, records go over the socket and the plain data
+// goes through the IoBuf of the direction
+pub struct TlsStream {
+    conn: rustls::Connection,
+    eof: bool,
+    closing: bool,
+}
+
+impl TlsStream {
+    pub fn server(config: &Arc<rustls::ServerConfig>) -> Result<TlsStream, rustls::Error> {
+        let conn = rustls::ServerConnection::new(config.clone())?;
+        Ok(TlsStream::new(conn.into()))
+    }
+
+    pub fn client(
+        config: &Arc<rustls::ClientConfig>,
+        server_name: &ServerName<'static>,
+    ) -> Result<TlsStream, rustls::Error> {
+        let conn = rustls::ClientConnection::new(config.clone(), server_name.clone())?;
+        Ok(TlsStream::new(conn.into()))
+    }
+
+    fn new(mut conn: rustls::Connection) -> TlsStream {
+        // write_from only hands over more data once the records are flushed
+        conn.set_buffer_limit(None);
+        TlsStream {
+            conn,
+            eof: false,
+            closing: false,
+        }
+    }
+
+    // writes pending records to fd, true once all of them are out
+    pub fn flush(&mut self, fd: i32) -> SysResult<bool> {
+        while self.conn.wants_write() {
+            match self.conn.write_tls(&mut Fd(fd)) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(ref e) => return Err(errno(e)),
+            }
+        }
+        Ok(true)
+    }
+
+    // decrypts records from fd into buf, true once the peer closed and all
+    // plain data has been handed over
+    pub fn read_into(&mut self, fd: i32, buf: &mut IoBuf) -> SysResult<bool> {
+        loop {
+            loop {
+                let mut reader = self.conn.reader();
+                match reader.fill_buf() {
+                    Ok(data) if !data.is_empty() => {
+                        let n = buf.write(data)?;
+                        if n == 0 {
+                            return Ok(false);
+                        }
+                        reader.consume(n);
+                    }
+                    Ok(_) => return Ok(true),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        if self.eof {
+                            return Ok(true);
+                        }
+                        break;
+                    }
+                    // closed without close_notify
+                    Err(_) => return Ok(true),
+                }
+            }
+            match self.conn.read_tls(&mut Fd(fd)) {
+                Ok(0) => self.eof = true,
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.flush(fd)?;
+                    return Ok(false);
+                }
+                Err(ref e) => return Err(errno(e)),
+            }
+            if let Err(e) = self.conn.process_new_packets() {
+                log!("tls error on fd {}: {}", fd, e);
+                // best effort to deliver the alert
+                let _ = self.flush(fd);
+                return Err(libc::EPROTO);
+            }
+            self.flush(fd)?;
+        }
+    }
+
+    // encrypts data from buf to fd, only taking more once the records are out
+    pub fn write_from(&mut self, buf: &mut IoBuf, fd: i32) -> SysResult<()> {
+        let mut chunk = [0u8; 16384];
+        while self.flush(fd)? && !buf.is_empty() {
+            let n = buf.read(&mut chunk)?;
+            if n == 0 {
+                break;
+            }
+            self.conn
+                .writer()
+                .write_all(&chunk[..n])
+                .map_err(|e| errno(&e))?;
+        }
+        Ok(())
+    }
+
+    // sends close_notify, true once it has been flushed
+    pub fn shutdown(&mut self, fd: i32) -> SysResult<bool> {
+        if !self.closing {
+            self.conn.send_close_notify();
+            self.closing = true;
+        }
+        self.flush(fd)
+    }
+}
@@ -0,0 +1,257 @@
+use libc;
+use std::net::IpAddr;
+use sys::SysResult;
+
+// how backends are reached through an upstream proxy
+pub enum Proto {
+    // RFC 1928, with RFC 1929 username/password auth if set
+    Socks5 { auth: Option<(String, String)> },
+    // user:pass goes out as Proxy-Authorization: Basic
+    HttpConnect { auth: Option<String> },
+}
+
+// largest CONNECT response head accepted
+const MAX_HTTP_HEAD: usize = 8192;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Step {
+    Greeting,
+    Auth,
+    Connect,
+    HttpResponse,
+}
+
+// the request/response exchange with the upstream proxy on a freshly
+// connected socket, driven by the readiness events of that socket
+pub struct Handshake {
+    step: Step,
+    send: Vec<u8>,
+    sent: usize,
+    recv: Vec<u8>,
+    need: usize,
+    auth: Option<(String, String)>,
+    host: String,
+    port: u16,
+}
+
+impl Handshake {
+    pub fn new(proto: &Proto, host: &str, port: u16) -> Handshake {
+        let mut handshake = Handshake {
+            step: Step::Greeting,
+            send: Vec::new(),
+            sent: 0,
+            recv: Vec::new(),
+            need: 0,
+            auth: None,
+            host: host.to_string(),
+            port,
+        };
+        match *proto {
+            Proto::Socks5 { ref auth } => {
+                let mut req = vec![5];
+                if auth.is_some() {
+                    req.extend_from_slice(&[2, 0, 2]);
+                } else {
+                    req.extend_from_slice(&[1, 0]);
+                }
+                handshake.auth = auth.clone();
+                handshake.request(Step::Greeting, req, 2);
+            }
+            Proto::HttpConnect { ref auth } => {
+                let target = if host.contains(':') {
+                    format!("[{}]:{}", host, port)
+                } else {
+                    format!("{}:{}", host, port)
+                };
+                let mut req = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+                if let Some(ref auth) = *auth {
+                    req += &format!("Proxy-Authorization: Basic {}\r\n", base64(auth.as_bytes()));
+                }
+                req += "\r\n";
+                // the head is read a byte at a time, its end is only known once seen
+                handshake.request(Step::HttpResponse, req.into_bytes(), 1);
+            }
+        }
+        handshake
+    }
+
+    // true once the tunnel is up, replies are read exactly so that no byte
+    // of the backend stream is consumed
+    pub fn advance(&mut self, fd: i32) -> SysResult<bool> {
+        loop {
+            while self.sent < self.send.len() {
+                let rest = &self.send[self.sent..];
+                match syscall!(libc::send(
+                    fd,
+                    rest.as_ptr() as *const _,
+                    rest.len(),
+                    libc::MSG_NOSIGNAL
+                )) {
+                    Ok(n) => self.sent += n as usize,
+                    Err(e) if e == libc::EAGAIN => return Ok(false),
+                    Err(e) => return Err(e),
+                }
+            }
+            while self.recv.len() < self.need {
+                let mut buf = [0u8; 512];
+                let want = (self.need - self.recv.len()).min(buf.len());
+                match syscall!(libc::recv(fd, buf.as_mut_ptr() as *mut _, want, 0)) {
+                    Ok(0) => return Err(libc::ECONNRESET),
+                    Ok(n) => self.recv.extend_from_slice(&buf[..n as usize]),
+                    Err(e) if e == libc::EAGAIN => return Ok(false),
+                    Err(e) => return Err(e),
+                }
+            }
+            let done = match self.step {
+                Step::HttpResponse => self.http_response()?,
+                _ => self.socks5_reply()?,
+            };
+            if done {
+                return Ok(true);
+            }
+        }
+    }
+
+    fn request(&mut self, step: Step, send: Vec<u8>, need: usize) {
+        self.step = step;
+        self.send = send;
+        self.sent = 0;
+        self.recv.clear();
+        self.need = need;
+    }
+
+    fn connect_request(&self) -> Vec<u8> {
+        let mut req = vec![5, 1, 0];
+        match self.host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                req.push(1);
+                req.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                req.push(4);
+                req.extend_from_slice(&ip.octets());
+            }
+            // names are resolved by the proxy
+            Err(_) => {
+                req.push(3);
+                req.push(self.host.len() as u8);
+                req.extend_from_slice(self.host.as_bytes());
+            }
+        }
+        req.push((self.port >> 8) as u8);
+        req.push(self.port as u8);
+        req
+    }
+
+    // handles a complete reply, true when the handshake is done
+    fn socks5_reply(&mut self) -> SysResult<bool> {
+        match self.step {
+            Step::Greeting => {
+                if self.recv[0] != 5 {
+                    return Err(libc::EPROTO);
+                }
+                match self.recv[1] {
+                    0 => {
+                        let req = self.connect_request();
+                        self.request(Step::Connect, req, 5);
+                    }
+                    2 if self.auth.is_some() => {
+                        let (user, pass) = self.auth.clone().unwrap();
+                        let mut req = vec![1, user.len() as u8];
+                        req.extend_from_slice(user.as_bytes());
+                        req.push(pass.len() as u8);
+                        req.extend_from_slice(pass.as_bytes());
+                        self.request(Step::Auth, req, 2);
+                    }
+                    _ => return Err(libc::EACCES),
+                }
+                Ok(false)
+            }
+            Step::Auth => {
+                if self.recv[1] != 0 {
+                    return Err(libc::EACCES);
+                }
+                let req = self.connect_request();
+                self.request(Step::Connect, req, 5);
+                Ok(false)
+            }
+            Step::HttpResponse => unreachable!(),
+            Step::Connect => {
+                if self.recv[0] != 5 {
+                    return Err(libc::EPROTO);
+                }
+                if self.recv[1] != 0 {
+                    return Err(socks5_errno(self.recv[1]));
+                }
+                // the first 5 bytes tell the length of the bound address
+                let len = match self.recv[3] {
+                    1 => 4 + 4 + 2,
+                    4 => 4 + 16 + 2,
+                    3 => 5 + self.recv[4] as usize + 2,
+                    _ => return Err(libc::EPROTO),
+                };
+                if self.recv.len() < len {
+                    self.need = len;
+                    return Ok(false);
+                }
+                Ok(true)
+            }
+        }
+    }
+
+    // true once the whole response head is in and says 2xx
+    fn http_response(&mut self) -> SysResult<bool> {
+        if !self.recv.ends_with(b"\r\n\r\n") {
+            if self.recv.len() >= MAX_HTTP_HEAD {
+                return Err(libc::EPROTO);
+            }
+            self.need += 1;
+            return Ok(false);
+        }
+        let head = String::from_utf8_lossy(&self.recv);
+        let mut status = head.split_whitespace();
+        let code = match (status.next(), status.next()) {
+            (Some(version), Some(code)) if version.starts_with("HTTP/1.") => code,
+            _ => return Err(libc::EPROTO),
+        };
+        match code.parse::<u16>() {
+            Ok(200..=299) => Ok(true),
+            Ok(403) | Ok(407) => Err(libc::EACCES),
+            Ok(502) => Err(libc::ECONNREFUSED),
+            Ok(504) => Err(libc::ETIMEDOUT),
+            _ => Err(libc::EPROTO),
+        }
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const TABLE: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).cloned().unwrap_or(0),
+            chunk.get(2).cloned().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(TABLE[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn socks5_errno(rep: u8) -> i32 {
+    match rep {
+        2 => libc::EACCES,
+        3 => libc::ENETUNREACH,
+        4 => libc::EHOSTUNREACH,
+        5 => libc::ECONNREFUSED,
+        6 => libc::ETIMEDOUT,
+        _ => libc::EPROTO,
+    }
+}
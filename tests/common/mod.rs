@@ -0,0 +1,239 @@
+// the built proxy and an echo backend for the integration tests, both on
+// ephemeral ports of the loopback
+use libc;
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{self, Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const IO_TIMEOUT: Duration = Duration::from_secs(2);
+
+// an echo server on a thread, one more thread per connection
+pub fn echo_backend(ip: &str) -> SocketAddr {
+    let listener = TcpListener::bind((ip, 0)).unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for conn in listener.incoming() {
+            let mut conn = match conn {
+                Ok(conn) => conn,
+                Err(_) => continue,
+            };
+            thread::spawn(move || {
+                let mut buf = [0u8; 4096];
+                loop {
+                    match conn.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => {
+                            if conn.write_all(&buf[..n]).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+// a backend that sends reply as soon as a client connects and closes right
+// after, without reading anything
+pub fn reply_backend(ip: &str, reply: Vec<u8>) -> SocketAddr {
+    let listener = TcpListener::bind((ip, 0)).unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for conn in listener.incoming() {
+            let mut conn = match conn {
+                Ok(conn) => conn,
+                Err(_) => continue,
+            };
+            let _ = conn.write_all(&reply);
+        }
+    });
+    addr
+}
+
+// a v4 listener that never answers a SYN: its backlog of one is taken by
+// a connection nobody accepts, the kernel drops the SYNs that come after
+pub struct Unanswered {
+    pub addr: SocketAddr,
+    fd: i32,
+    _queued: TcpStream,
+}
+
+impl Unanswered {
+    pub fn new() -> Unanswered {
+        let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+        assert!(fd >= 0);
+        let mut sin: libc::sockaddr_in = unsafe { mem::zeroed() };
+        sin.sin_family = libc::AF_INET as libc::sa_family_t;
+        sin.sin_addr.s_addr = u32::from_ne_bytes([127, 0, 0, 1]);
+        let len = mem::size_of_val(&sin) as libc::socklen_t;
+        let sa = &sin as *const _ as *const libc::sockaddr;
+        assert_eq!(unsafe { libc::bind(fd, sa, len) }, 0);
+        assert_eq!(unsafe { libc::listen(fd, 0) }, 0);
+        let mut sin: libc::sockaddr_in = unsafe { mem::zeroed() };
+        let mut len = mem::size_of_val(&sin) as libc::socklen_t;
+        let sa = &mut sin as *mut _ as *mut libc::sockaddr;
+        assert_eq!(unsafe { libc::getsockname(fd, sa, &mut len) }, 0);
+        let addr = SocketAddr::from(([127, 0, 0, 1], u16::from_be(sin.sin_port)));
+        let queued = TcpStream::connect_timeout(&addr, IO_TIMEOUT).unwrap();
+        Unanswered {
+            addr,
+            fd,
+            _queued: queued,
+        }
+    }
+}
+
+impl Drop for Unanswered {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+// the sockets of this host still waiting for the SYN-ACK of addr, v4 only
+pub fn connecting_to(addr: SocketAddr) -> usize {
+    let port = format!(":{:04X}", addr.port());
+    fs::read_to_string("/proc/net/tcp")
+        .unwrap()
+        .lines()
+        .skip(1)
+        .filter(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            // remote address and state, 02 is SYN_SENT
+            fields[2].ends_with(&port) && fields[3] == "02"
+        })
+        .count()
+}
+
+// a port nothing listens on, connects to it are refused
+pub fn closed_port(ip: &str) -> SocketAddr {
+    TcpListener::bind((ip, 0)).unwrap().local_addr().unwrap()
+}
+
+pub struct Proxy {
+    child: Child,
+    pub addr: SocketAddr,
+    // the listen socket the proxy inherited, kept open so its port stays
+    _listener: TcpListener,
+}
+
+impl Proxy {
+    // the proxy with args, on a listen socket bound here and handed over
+    // with -inherit-fds. Clients may connect right away, they wait in the
+    // backlog until the proxy is up
+    pub fn start(ip: &str, args: &[&str]) -> Proxy {
+        let listener = TcpListener::bind((ip, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let fd = listener.as_raw_fd();
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_tcpproxy"));
+        cmd.arg("-inherit-fds")
+            .arg(fd.to_string())
+            .args(args)
+            .stdout(Stdio::null());
+        // std opens it close-on-exec
+        unsafe {
+            cmd.pre_exec(move || {
+                libc::fcntl(fd, libc::F_SETFD, 0);
+                Ok(())
+            });
+        }
+        Proxy {
+            child: cmd.spawn().unwrap(),
+            addr,
+            _listener: listener,
+        }
+    }
+
+    // the open fds of the proxy process
+    pub fn fds(&self) -> Vec<i32> {
+        let dir = format!("/proc/{}/fd", self.child.id());
+        let mut fds: Vec<i32> = fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .collect();
+        fds.sort();
+        fds
+    }
+
+    // the open fds once they are expect, or stopped changing for a while
+    // without one. Connections that are done on the client side may take
+    // the proxy a moment to close
+    pub fn settled_fds(&self, expect: Option<&[i32]>) -> Vec<i32> {
+        let deadline = Instant::now() + IO_TIMEOUT;
+        let (mut last, mut same) = (self.fds(), 0);
+        while Instant::now() < deadline {
+            match expect {
+                Some(expect) if last == expect => break,
+                None if same == 10 => break,
+                _ => {}
+            }
+            thread::sleep(Duration::from_millis(10));
+            let fds = self.fds();
+            same = if fds == last { same + 1 } else { 0 };
+            last = fds;
+        }
+        last
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// a path for the control socket of a proxy, unique to the test
+pub fn control_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("tcpproxy-{}-{}.sock", process::id(), name))
+}
+
+// the reply to a control command, the socket may take the proxy a moment
+// to create
+pub fn control(path: &PathBuf, command: &str) -> String {
+    let deadline = Instant::now() + IO_TIMEOUT;
+    let mut conn = loop {
+        match UnixStream::connect(path) {
+            Ok(conn) => break conn,
+            Err(e) if Instant::now() > deadline => panic!("control socket: {}", e),
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+    conn.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+    conn.write_all(format!("{}\n", command).as_bytes()).unwrap();
+    let mut reply = String::new();
+    conn.read_to_string(&mut reply).unwrap();
+    reply
+}
+
+// what comes back for msg, the client closes its side once msg is out
+pub fn round_trip(addr: SocketAddr, msg: &[u8]) -> Vec<u8> {
+    let mut conn = TcpStream::connect_timeout(&addr, IO_TIMEOUT).unwrap();
+    conn.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+    conn.write_all(msg).unwrap();
+    conn.shutdown(Shutdown::Write).unwrap();
+    let mut reply = Vec::new();
+    conn.read_to_end(&mut reply).unwrap();
+    reply
+}
+
+// what a client the proxy closes without a backend gets: nothing. The
+// close may come before or amid its send, a reset is no error here
+pub fn turned_away(addr: SocketAddr, msg: &[u8]) -> Vec<u8> {
+    let mut conn = TcpStream::connect_timeout(&addr, IO_TIMEOUT).unwrap();
+    conn.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+    let _ = conn.write_all(msg);
+    let mut reply = Vec::new();
+    let _ = conn.read_to_end(&mut reply);
+    reply
+}
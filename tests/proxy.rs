@@ -0,0 +1,175 @@
+extern crate libc;
+
+mod common;
+
+use common::{
+    closed_port, connecting_to, control, control_path, echo_backend, reply_backend, round_trip,
+    turned_away, Proxy, Unanswered,
+};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn echo_round_trip() {
+    let backend = echo_backend("127.0.0.1");
+    let proxy = Proxy::start("127.0.0.1", &["-d", &backend.to_string()]);
+    let msg: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+    assert_eq!(round_trip(proxy.addr, &msg), msg);
+}
+
+#[test]
+fn v6_client_to_v4_backend() {
+    let backend = echo_backend("127.0.0.1");
+    let proxy = Proxy::start("::1", &["-d", &backend.to_string()]);
+    assert!(proxy.addr.is_ipv6());
+    assert_eq!(
+        round_trip(proxy.addr, b"over v6, on to v4"),
+        b"over v6, on to v4"
+    );
+}
+
+#[test]
+fn v4_client_to_v6_backend() {
+    let backend = echo_backend("::1");
+    let proxy = Proxy::start("127.0.0.1", &["-d", &backend.to_string()]);
+    assert!(backend.is_ipv6());
+    assert_eq!(
+        round_trip(proxy.addr, b"over v4, on to v6"),
+        b"over v4, on to v6"
+    );
+}
+
+// runs batch through the proxy after a first connection, which sets up
+// whatever the proxy keeps for good, and compares the open fds before and
+// after
+fn assert_no_fd_leak<F: Fn(&Proxy)>(proxy: &Proxy, batch: F) {
+    batch(proxy);
+    let before = proxy.settled_fds(None);
+    batch(proxy);
+    assert_eq!(proxy.settled_fds(Some(&before)), before);
+}
+
+#[test]
+fn no_fd_leak_on_served_connections() {
+    let backend = echo_backend("127.0.0.1");
+    let proxy = Proxy::start("127.0.0.1", &["-d", &backend.to_string()]);
+    assert_no_fd_leak(&proxy, |proxy| {
+        let clients: Vec<_> = (0..20)
+            .map(|i| {
+                let addr = proxy.addr;
+                thread::spawn(move || round_trip(addr, format!("client {}", i).as_bytes()))
+            })
+            .collect();
+        for client in clients {
+            assert!(client.join().unwrap().starts_with(b"client "));
+        }
+        // a client that goes away without a word
+        drop(TcpStream::connect(proxy.addr).unwrap());
+    });
+}
+
+#[test]
+fn no_fd_leak_on_failed_connects() {
+    let backend = closed_port("127.0.0.1");
+    let proxy = Proxy::start("127.0.0.1", &["-d", &backend.to_string()]);
+    assert_no_fd_leak(&proxy, |proxy| {
+        for _ in 0..20 {
+            assert_eq!(turned_away(proxy.addr, b"nobody there"), b"");
+        }
+    });
+}
+
+#[test]
+fn reply_is_whole_when_the_backend_closes_at_once() {
+    let reply: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+    let backend = reply_backend("127.0.0.1", reply.clone());
+    let proxy = Proxy::start("127.0.0.1", &["-d", &backend.to_string()]);
+    for _ in 0..5 {
+        let mut conn = TcpStream::connect(proxy.addr).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut got = Vec::new();
+        conn.read_to_end(&mut got).unwrap();
+        assert_eq!(got.len(), reply.len());
+        assert!(got == reply);
+    }
+}
+
+#[test]
+fn connect_timeout_closes_the_client() {
+    let backend = Unanswered::new();
+    let proxy = Proxy::start(
+        "127.0.0.1",
+        &["-d", &backend.addr.to_string(), "-ct", "300ms"],
+    );
+    let start = Instant::now();
+    assert_eq!(turned_away(proxy.addr, b"anyone?"), b"");
+    let took = start.elapsed();
+    assert!(took >= Duration::from_millis(250), "{:?}", took);
+    assert!(took < Duration::from_millis(1500), "{:?}", took);
+    // the SYN that went unanswered is given up with its socket, which may
+    // close a moment after the client
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while connecting_to(backend.addr) > 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(connecting_to(backend.addr), 0);
+}
+
+#[test]
+fn connlimit_caps_connects_in_flight() {
+    let backend = Unanswered::new();
+    let args = [
+        "-d",
+        &backend.addr.to_string(),
+        "-connlimit",
+        "2",
+        "-ct",
+        "300ms",
+    ];
+    let proxy = Proxy::start("127.0.0.1", &args);
+    let clients: Vec<_> = (0..5)
+        .map(|_| TcpStream::connect(proxy.addr).unwrap())
+        .collect();
+    // five clients with two at a time take three rounds of -ct
+    let deadline = Instant::now() + Duration::from_millis(1200);
+    let mut most = 0;
+    while Instant::now() < deadline {
+        most = most.max(connecting_to(backend.addr));
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(most, 2);
+    drop(clients);
+}
+
+#[test]
+fn conns_lists_a_live_connection() {
+    let backend = echo_backend("127.0.0.1");
+    let path = control_path("conns");
+    let path_arg = path.to_str().unwrap();
+    let proxy = Proxy::start(
+        "127.0.0.1",
+        &["-d", &backend.to_string(), "-control", path_arg],
+    );
+    let mut conn = TcpStream::connect(proxy.addr).unwrap();
+    conn.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    conn.write_all(b"ping").unwrap();
+    let mut pong = [0u8; 4];
+    conn.read_exact(&mut pong).unwrap();
+    let reply = control(&path, "CONNS");
+    let mut lines = reply.lines();
+    assert_eq!(lines.next(), Some("OK 1"));
+    let entry = lines.next().unwrap();
+    let client = format!("\"client\":\"{}\"", conn.local_addr().unwrap());
+    assert!(entry.contains(&client), "{}", entry);
+    assert!(entry.contains("\"state\":\"streaming\""), "{}", entry);
+    assert!(
+        entry.contains("\"bytes_in\":4,\"bytes_out\":4"),
+        "{}",
+        entry
+    );
+    drop(conn);
+    drop(proxy);
+    let _ = std::fs::remove_file(&path);
+}